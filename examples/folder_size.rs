@@ -58,10 +58,7 @@ async fn main() {
     let bind: u16 = *matches.get_one("port").unwrap();
     let addr: SocketAddr = ([0, 0, 0, 0], bind).into();
 
-    let server_options = ServerOptions {
-        addr,
-        authorization: Authorization::None,
-    };
+    let server_options = ServerOptions::new(addr, Authorization::None);
     println!("starting exporter with options {:?}", addr);
 
     render_prometheus(
@@ -80,7 +77,7 @@ async fn main() {
                 .with_help("Size of the folder")
                 .build();
 
-            for folder in &vec!["/var/log", "/tmp"] {
+            for folder in &["/var/log", "/tmp"] {
                 pc.render_and_append_instance(
                     &PrometheusInstance::new()
                         .with_label("folder", folder.as_ref())
@@ -95,5 +92,6 @@ async fn main() {
             Ok(pc.render())
         },
     )
-    .await;
+    .await
+    .expect("the server failed");
 }
@@ -23,10 +23,7 @@ async fn main() {
     let addr: SocketAddr = ([0, 0, 0, 0], 32221).into();
     let password = "SimplePassword".to_owned();
 
-    let server_options = ServerOptions {
-        addr,
-        authorization: Authorization::Basic(password),
-    };
+    let server_options = ServerOptions::new(addr, Authorization::Basic(password));
     println!("starting exporter with options {:?}", addr);
 
     render_prometheus(
@@ -55,5 +52,6 @@ async fn main() {
                 .render())
         },
     )
-    .await;
+    .await
+    .expect("the server failed");
 }
@@ -0,0 +1,234 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// An IP network in CIDR notation, like `10.0.0.0/8` or `fd00::/8`.
+/// A bare address is a network of a single host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid network {0}, expected an address or a CIDR like 10.0.0.0/8")]
+pub struct InvalidIpNet(String);
+
+impl IpNet {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, canonical(addr)) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32);
+                let mask = mask.unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32);
+                let mask = mask.unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = InvalidIpNet;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidIpNet(s.to_owned());
+
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse::<u8>().map_err(|_| invalid())?,
+            None => max_prefix_len,
+        };
+        if prefix_len > max_prefix_len {
+            return Err(invalid());
+        }
+
+        match canonical(addr) {
+            // an IPv4-mapped network is the IPv4 one
+            IpAddr::V4(v4) if addr.is_ipv6() => Ok(Self {
+                addr: IpAddr::V4(v4),
+                prefix_len: prefix_len.checked_sub(96).ok_or_else(invalid)?,
+            }),
+            _ => Ok(Self { addr, prefix_len }),
+        }
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// IPv4 clients of a dual stack socket show up as IPv4-mapped IPv6
+/// addresses, match them against the IPv4 networks.
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+        IpAddr::V4(_) => addr,
+    }
+}
+
+/// Restricts the clients allowed to reach the server by IP address.
+/// The check happens before the authorization.
+///
+/// A client matching a denied network is refused with `403 Forbidden`.
+/// If any network is allowed, the client must match one of them too.
+/// Connections without an IP address, like the ones on Unix domain
+/// sockets, are not checked.
+///
+/// # Examples
+///
+/// ```
+/// use prometheus_exporter_base::prelude::*;
+///
+/// // only the Prometheus subnet, but not its gateway
+/// let access_control = AccessControl::new()
+///     .with_allow("10.1.0.0/16".parse().unwrap())
+///     .with_deny("10.1.0.1".parse().unwrap());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessControl {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+    /// The reverse proxies whose `X-Forwarded-For` header is trusted to
+    /// tell the address of the client.
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+impl AccessControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_allow(self, network: IpNet) -> Self {
+        let mut allow = self.allow;
+        allow.push(network);
+
+        Self { allow, ..self }
+    }
+
+    pub fn with_deny(self, network: IpNet) -> Self {
+        let mut deny = self.deny;
+        deny.push(network);
+
+        Self { deny, ..self }
+    }
+
+    /// Trusts the `X-Forwarded-For` header of the requests coming from
+    /// `network`.
+    pub fn with_trusted_proxy(self, network: IpNet) -> Self {
+        let mut trusted_proxies = self.trusted_proxies;
+        trusted_proxies.push(network);
+
+        Self {
+            trusted_proxies,
+            ..self
+        }
+    }
+
+    /// The address of the client. `X-Forwarded-For` is read from the
+    /// right, skipping the trusted proxies, so a client can't spoof its
+    /// address by sending the header itself.
+    pub(crate) fn client_addr(&self, peer: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+        let is_trusted = |addr| self.trusted_proxies.iter().any(|net| net.contains(addr));
+
+        let mut client = peer;
+        if !is_trusted(client) {
+            return client;
+        }
+        for hop in forwarded_for.unwrap_or_default().rsplit(',') {
+            match hop.trim().parse::<IpAddr>() {
+                Ok(addr) => client = addr,
+                // garbage from the client, stop at the last trusted hop
+                Err(_) => break,
+            }
+            if !is_trusted(client) {
+                break;
+            }
+        }
+
+        client
+    }
+
+    pub(crate) fn is_allowed(&self, client: IpAddr) -> bool {
+        !self.deny.iter().any(|net| net.contains(client))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(client)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn net(s: &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ip_net() {
+        assert!(net("10.0.0.0/8").contains(ip("10.200.3.4")));
+        assert!(!net("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(net("10.1.2.3").contains(ip("10.1.2.3")));
+        assert!(!net("10.1.2.3").contains(ip("10.1.2.4")));
+        assert!(net("0.0.0.0/0").contains(ip("192.168.1.1")));
+        assert!(!net("0.0.0.0/0").contains(ip("fd00::1")));
+        assert!(net("fd00::/8").contains(ip("fdab::1")));
+        assert!(net("10.0.0.0/8").contains(ip("::ffff:10.0.0.1")));
+        assert_eq!(net("::ffff:10.0.0.0/104").to_string(), "10.0.0.0/8");
+
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("10.0.0/8".parse::<IpNet>().is_err());
+        assert!("fd00::/x".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        let everyone = AccessControl::new();
+        assert!(everyone.is_allowed(ip("1.2.3.4")));
+
+        let access_control = AccessControl::new()
+            .with_allow(net("10.1.0.0/16"))
+            .with_deny(net("10.1.0.1"));
+        assert!(access_control.is_allowed(ip("10.1.5.5")));
+        assert!(!access_control.is_allowed(ip("10.1.0.1")));
+        assert!(!access_control.is_allowed(ip("10.2.0.1")));
+    }
+
+    #[test]
+    fn test_client_addr() {
+        let access_control = AccessControl::new().with_trusted_proxy(net("10.0.0.0/24"));
+
+        // untrusted peers can't claim another address
+        assert_eq!(
+            access_control.client_addr(ip("1.2.3.4"), Some("10.1.0.5")),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            access_control.client_addr(ip("10.0.0.1"), None),
+            ip("10.0.0.1")
+        );
+        assert_eq!(
+            access_control.client_addr(ip("10.0.0.1"), Some("6.6.6.6, 10.1.0.5, 10.0.0.2")),
+            ip("10.1.0.5")
+        );
+        assert_eq!(
+            access_control.client_addr(ip("10.0.0.1"), Some("garbage, 10.0.0.2")),
+            ip("10.0.0.2")
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use http::{Method, StatusCode, Version};
+use serde_json::json;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The `log` target of the access log lines, to route them apart from
+/// the other logs, for example with
+/// `RUST_LOG=prometheus_exporter_base::access_log=info`.
+pub const ACCESS_LOG_TARGET: &str = "prometheus_exporter_base::access_log";
+
+/// How the access log lines are written. Each request gets one line,
+/// logged at the info level with the [`ACCESS_LOG_TARGET`] target. The
+/// request headers are never logged.
+///
+/// [`ACCESS_LOG_TARGET`]: constant.ACCESS_LOG_TARGET.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// The Common Log Format followed by the latency in seconds:
+    ///
+    /// `10.0.0.1 - - [18/Oct/2026:21:50:11 +0000] "GET /metrics HTTP/1.1" 200 5120 0.004`
+    Common,
+    /// A JSON object with the `time`, `client`, `peer`, `user`,
+    /// `method`, `path`, `query`, `version`, `status`, `bytes` and
+    /// `latency_seconds` fields.
+    Json,
+}
+
+/// What is logged about a request.
+#[derive(Debug)]
+pub(crate) struct AccessLogEntry<'a> {
+    pub(crate) time: SystemTime,
+    /// The client address, taken from `X-Forwarded-For` for the
+    /// trusted proxies.
+    pub(crate) client: Option<IpAddr>,
+    pub(crate) peer: Option<SocketAddr>,
+    pub(crate) user: Option<&'a str>,
+    pub(crate) method: &'a Method,
+    pub(crate) path: &'a str,
+    pub(crate) query: Option<&'a str>,
+    pub(crate) version: Version,
+    pub(crate) status: StatusCode,
+    /// The size of the response body, `None` if streamed.
+    pub(crate) bytes: Option<u64>,
+    pub(crate) latency: Duration,
+}
+
+impl AccessLogEntry<'_> {
+    pub(crate) fn render(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Common => self.render_common(),
+            AccessLogFormat::Json => self.render_json(),
+        }
+    }
+
+    fn render_common(&self) -> String {
+        let target = match self.query {
+            Some(query) => format!("{}?{}", self.path, query),
+            None => self.path.to_owned(),
+        };
+        let (year, month, day, hour, minute, second, _) = utc(self.time);
+        let user = match self.user {
+            Some(user) if !user.is_empty() => escape(user),
+            _ => "-".to_owned(),
+        };
+
+        format!(
+            "{} - {} [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] \"{} {} {:?}\" {} {} {:.3}",
+            self.client
+                .map_or_else(|| "-".to_owned(), |client| client.to_string()),
+            user,
+            day,
+            MONTHS[month as usize - 1],
+            year,
+            hour,
+            minute,
+            second,
+            self.method,
+            escape(&target),
+            self.version,
+            self.status.as_u16(),
+            self.bytes
+                .map_or_else(|| "-".to_owned(), |bytes| bytes.to_string()),
+            self.latency.as_secs_f64()
+        )
+    }
+
+    fn render_json(&self) -> String {
+        let (year, month, day, hour, minute, second, millis) = utc(self.time);
+
+        json!({
+            "time": format!(
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+                year, month, day, hour, minute, second, millis
+            ),
+            "client": self.client.map(|client| client.to_string()),
+            "peer": self.peer.map(|peer| peer.to_string()),
+            "user": self.user,
+            "method": self.method.as_str(),
+            "path": self.path,
+            "query": self.query,
+            "version": format!("{:?}", self.version),
+            "status": self.status.as_u16(),
+            "bytes": self.bytes,
+            "latency_seconds": self.latency.as_secs_f64(),
+        })
+        .to_string()
+    }
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Escapes the quotes, backslashes and control characters so a client
+/// can't forge log lines.
+fn escape(s: &str) -> String {
+    s.chars().flat_map(char::escape_default).collect()
+}
+
+/// The UTC year, month, day, hour, minute, second and millisecond of
+/// `time`.
+fn utc(time: SystemTime) -> (i64, u32, u32, u32, u32, u32, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86_400) as i64, (secs % 86_400) as u32);
+
+    // days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry<'a>(method: &'a Method, user: Option<&'a str>) -> AccessLogEntry<'a> {
+        AccessLogEntry {
+            time: UNIX_EPOCH + Duration::from_millis(1_792_360_211_042),
+            client: Some("10.0.0.1".parse().unwrap()),
+            peer: Some("10.0.0.2:51234".parse().unwrap()),
+            user,
+            method,
+            path: "/probe",
+            query: Some("target=\"db\""),
+            version: Version::HTTP_11,
+            status: StatusCode::OK,
+            bytes: Some(5120),
+            latency: Duration::from_millis(4),
+        }
+    }
+
+    #[test]
+    fn test_render_common() {
+        let get = Method::GET;
+        assert_eq!(
+            entry(&get, None).render(AccessLogFormat::Common),
+            "10.0.0.1 - - [18/Oct/2026:21:50:11 +0000] \"GET /probe?target=\\\"db\\\" HTTP/1.1\" 200 5120 0.004"
+        );
+
+        let streamed = AccessLogEntry {
+            client: None,
+            peer: None,
+            bytes: None,
+            ..entry(&get, Some("alice"))
+        };
+        assert!(streamed
+            .render(AccessLogFormat::Common)
+            .starts_with("- - alice ["));
+        assert!(streamed
+            .render(AccessLogFormat::Common)
+            .ends_with(" 200 - 0.004"));
+    }
+
+    #[test]
+    fn test_render_json() {
+        let get = Method::GET;
+        let rendered = entry(&get, Some("")).render(AccessLogFormat::Json);
+        let parsed: serde_json::Value = serde_json::from_str(&rendered).unwrap();
+
+        assert_eq!(parsed["time"], "2026-10-18T21:50:11.042Z");
+        assert_eq!(parsed["client"], "10.0.0.1");
+        assert_eq!(parsed["peer"], "10.0.0.2:51234");
+        assert_eq!(parsed["user"], "");
+        assert_eq!(parsed["method"], "GET");
+        assert_eq!(parsed["path"], "/probe");
+        assert_eq!(parsed["query"], "target=\"db\"");
+        assert_eq!(parsed["version"], "HTTP/1.1");
+        assert_eq!(parsed["status"], 200);
+        assert_eq!(parsed["bytes"], 5120);
+        assert_eq!(parsed["latency_seconds"], 0.004);
+    }
+
+    #[test]
+    fn test_utc() {
+        assert_eq!(utc(UNIX_EPOCH), (1970, 1, 1, 0, 0, 0, 0));
+        // leap day
+        assert_eq!(
+            utc(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            (2000, 2, 29, 0, 0, 0, 0)
+        );
+    }
+}
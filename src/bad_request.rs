@@ -0,0 +1,24 @@
+use thiserror::Error;
+
+/// Return it, boxed, from a collector to answer the scrape with
+/// `400 Bad Request` and the message as body instead of
+/// `500 Internal Server Error`.
+///
+/// Example:
+///
+/// ```
+/// use prometheus_exporter_base::prelude::*;
+/// use std::error::Error;
+///
+/// fn parse_depth(context: &ScrapeContext) -> Result<u32, Box<dyn Error + Send + Sync>> {
+///     match context.query_param("depth") {
+///         Some(depth) => Ok(depth
+///             .parse()
+///             .map_err(|_| BadRequest(format!("invalid depth {}", depth)))?),
+///         None => Ok(1),
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{0}")]
+pub struct BadRequest(pub String);
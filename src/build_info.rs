@@ -0,0 +1,90 @@
+use crate::{MetricType, PrometheusInstance, PrometheusMetric};
+
+/// The build metadata of an exporter, rendered as the
+/// `<namespace>_build_info` gauge whose value is always 1.
+///
+/// Create it with the [`build_info!`] macro so it picks up the
+/// compile-time data of your crate rather than this one's.
+///
+/// [`build_info!`]: macro.build_info.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub revision: &'static str,
+    pub branch: &'static str,
+    pub rustc_version: &'static str,
+}
+
+impl BuildInfo {
+    pub fn render(&self, namespace: &str) -> String {
+        let name = format!("{}_build_info", namespace);
+        let help = format!(
+            "A metric with a constant '1' value labeled by version, revision, branch, and rustc_version from which {} was built",
+            namespace
+        );
+
+        PrometheusMetric::build()
+            .with_name(&name)
+            .with_metric_type(MetricType::Gauge)
+            .with_help(&help)
+            .build()
+            .render_and_append_instance(
+                &PrometheusInstance::new()
+                    .with_label("version", self.version)
+                    .with_label("revision", self.revision)
+                    .with_label("branch", self.branch)
+                    .with_label("rustc_version", self.rustc_version)
+                    .with_value(1),
+            )
+            .render()
+    }
+}
+
+/// Builds the [`BuildInfo`] of the calling crate. The version comes from
+/// `Cargo.toml` while the revision, the branch and the compiler version are
+/// read from the `BUILD_REVISION`, `BUILD_BRANCH` and `BUILD_RUSTC_VERSION`
+/// environment variables at compile time, `unknown` if missing. Set them
+/// from your build script, for example with
+/// `println!("cargo:rustc-env=BUILD_REVISION={}", revision)`.
+///
+/// [`BuildInfo`]: struct.BuildInfo.html
+///
+/// # Examples
+///
+/// ```
+/// use prometheus_exporter_base::build_info;
+///
+/// let rendered = build_info!().render("my_exporter");
+/// assert!(rendered.contains("my_exporter_build_info{version=\""));
+/// ```
+#[macro_export]
+macro_rules! build_info {
+    () => {
+        $crate::BuildInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            revision: option_env!("BUILD_REVISION").unwrap_or("unknown"),
+            branch: option_env!("BUILD_BRANCH").unwrap_or("unknown"),
+            rustc_version: option_env!("BUILD_RUSTC_VERSION").unwrap_or("unknown"),
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let build_info = BuildInfo {
+            version: "1.4.0",
+            revision: "4a109b3",
+            branch: "master",
+            rustc_version: "1.65.0",
+        };
+
+        assert!(build_info.render("pippo").ends_with(
+            "# TYPE pippo_build_info gauge\n\
+            pippo_build_info{version=\"1.4.0\",revision=\"4a109b3\",branch=\"master\",rustc_version=\"1.65.0\"} 1\n"
+        ));
+    }
+}
@@ -0,0 +1,141 @@
+use futures_util::FutureExt;
+use log::error;
+use std::any::Any;
+use std::error::Error;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, Ordering};
+use thiserror::Error;
+
+/// The panics caught since the process started, whatever server
+/// caught them.
+static PANICS: AtomicU64 = AtomicU64::new(0);
+
+/// Stands for a panicked collector. Its message does not carry the
+/// panic payload so it can be sent to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("the collector panicked")]
+pub(crate) struct CollectorPanicked {}
+
+/// Calls `collect` and awaits it, turning a panic into a
+/// [`CollectorPanicked`] error after logging its payload.
+pub(crate) async fn catch_panic<C, Fut, T>(collect: C) -> Result<T, Box<dyn Error + Send + Sync>>
+where
+    C: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, Box<dyn Error + Send + Sync>>>,
+{
+    catch(None, collect).await
+}
+
+/// Works like [`catch_panic`] for the collector registered as `name`,
+/// which its span is tagged with.
+pub(crate) async fn catch_collector_panic<C, Fut, T>(
+    name: &str,
+    collect: C,
+) -> Result<T, Box<dyn Error + Send + Sync>>
+where
+    C: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, Box<dyn Error + Send + Sync>>>,
+{
+    catch(Some(name), collect).await
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+async fn catch<C, Fut, T>(
+    collector: Option<&str>,
+    collect: C,
+) -> Result<T, Box<dyn Error + Send + Sync>>
+where
+    C: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, Box<dyn Error + Send + Sync>>>,
+{
+    // calling the closure is part of the future so its panics are caught too
+    let collect = async move { collect().await };
+    #[cfg(feature = "tracing")]
+    let span = match collector {
+        Some(collector) => tracing::info_span!(
+            "collect",
+            collector = %collector,
+            outcome = tracing::field::Empty
+        ),
+        None => tracing::info_span!("collect", outcome = tracing::field::Empty),
+    };
+    #[cfg(feature = "tracing")]
+    let collect = tracing::Instrument::instrument(collect, span.clone());
+
+    let outcome = match AssertUnwindSafe(collect).catch_unwind().await {
+        Ok(outcome) => outcome,
+        Err(payload) => {
+            record_panic(payload.as_ref());
+            Err(CollectorPanicked {}.into())
+        }
+    };
+    #[cfg(feature = "tracing")]
+    span.record(
+        "outcome",
+        match &outcome {
+            Ok(_) => "ok",
+            Err(err) if err.is::<CollectorPanicked>() => "panicked",
+            Err(_) => "failed",
+        },
+    );
+
+    outcome
+}
+
+/// Logs and counts a caught panic.
+pub(crate) fn record_panic(payload: &(dyn Any + Send)) {
+    PANICS.fetch_add(1, Ordering::Relaxed);
+    error!("panic caught == {}", panic_message(payload));
+}
+
+pub(crate) fn panics() -> u64 {
+    PANICS.load(Ordering::Relaxed)
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic payload"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_catch_panic() {
+        let before = panics();
+
+        let ok = catch_panic(|| async { Ok::<_, Box<dyn Error + Send + Sync>>(42) }).await;
+        assert_eq!(ok.unwrap(), 42);
+
+        let in_future = catch_panic(|| async {
+            let size: Result<u64, std::io::Error> =
+                std::fs::metadata("/nonexistent").map(|m| m.len());
+            Ok::<_, Box<dyn Error + Send + Sync>>(size.expect("cannot calculate folder size"))
+        })
+        .await;
+        assert!(in_future.unwrap_err().is::<CollectorPanicked>());
+
+        let in_closure = catch_panic(|| -> std::future::Ready<Result<u64, _>> {
+            panic!("before the future {}", 1)
+        })
+        .await;
+        assert!(in_closure.unwrap_err().is::<CollectorPanicked>());
+
+        // other tests may panic concurrently
+        assert!(panics() >= before + 2);
+    }
+
+    #[test]
+    fn test_panic_message() {
+        assert_eq!(panic_message(&"static"), "static");
+        assert_eq!(panic_message(&"owned".to_owned()), "owned");
+        assert_eq!(panic_message(&42), "unknown panic payload");
+    }
+}
@@ -0,0 +1,304 @@
+use crate::{MetricType, PrometheusInstance, PrometheusMetric};
+use hyper::Body;
+use log::warn;
+use std::error::Error;
+use thiserror::Error;
+use tokio::sync::{mpsc, OwnedSemaphorePermit};
+
+/// The result of a collection that may have partially failed: the
+/// metrics rendered successfully along with the errors met along the way.
+/// The server renders the metrics, logs the errors and reports how many
+/// there were with the `exporter_scrape_errors` gauge.
+///
+/// Collectors returning a plain `String` are converted into a
+/// `Collection` without errors. Large expositions can be streamed
+/// instead, check [`Collection::streaming`].
+///
+/// [`Collection::streaming`]: #method.streaming
+///
+/// # Examples
+///
+/// ```
+/// use prometheus_exporter_base::prelude::*;
+///
+/// let mut collection = Collection::new();
+/// for folder in &["/var/log", "/does/not/exist"] {
+///     match std::fs::metadata(folder) {
+///         Ok(metadata) => collection.push_metrics(
+///             &PrometheusMetric::build()
+///                 .with_name("folder_size")
+///                 .with_metric_type(MetricType::Gauge)
+///                 .with_help("Size of the folder")
+///                 .build()
+///                 .render_and_append_instance(
+///                     &PrometheusInstance::new()
+///                         .with_label("folder", *folder)
+///                         .with_value(metadata.len()),
+///                 )
+///                 .render(),
+///         ),
+///         Err(err) => collection.push_error(err),
+///     }
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct Collection {
+    pub metrics: String,
+    pub errors: Vec<Box<dyn Error + Send + Sync>>,
+    /// The metrics still to come, sent after `metrics`.
+    stream: Option<mpsc::Receiver<StreamItem>>,
+    /// The collection slot, held until the end of the stream.
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+#[derive(Debug)]
+enum StreamItem {
+    Metrics(String),
+    Error(Box<dyn Error + Send + Sync>),
+}
+
+/// Returned by [`MetricsSender`] once the scrape is gone, for example
+/// because the client disconnected. Stop rendering when you get it.
+///
+/// [`MetricsSender`]: struct.MetricsSender.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("the scrape was aborted")]
+pub struct ScrapeAborted {}
+
+/// Sends rendered metrics to a streaming [`Collection`]. Sending waits
+/// while the client is slower than the collector so the memory used by
+/// the scrape stays bounded.
+///
+/// [`Collection`]: struct.Collection.html
+#[derive(Debug, Clone)]
+pub struct MetricsSender {
+    tx: mpsc::Sender<StreamItem>,
+}
+
+impl MetricsSender {
+    /// Sends a rendered metric family.
+    pub async fn send(&self, rendered: String) -> Result<(), ScrapeAborted> {
+        self.tx
+            .send(StreamItem::Metrics(rendered))
+            .await
+            .map_err(|_| ScrapeAborted {})
+    }
+
+    /// Records an error, it's counted by the `exporter_scrape_errors`
+    /// gauge sent at the end of the stream.
+    pub async fn send_error<E>(&self, err: E) -> Result<(), ScrapeAborted>
+    where
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        self.tx
+            .send(StreamItem::Error(err.into()))
+            .await
+            .map_err(|_| ScrapeAborted {})
+    }
+}
+
+impl Collection {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a collection whose metrics are sent through the returned
+    /// [`MetricsSender`] and forwarded to the client as they come, with
+    /// at most `buffer` of them waiting in memory. The stream ends when
+    /// every sender is dropped.
+    ///
+    /// Streams can't be shared between scrapes: they are buffered in
+    /// memory when the scrape cache, scrape coalescing or polling are
+    /// enabled, and for probes. Streamed responses are not compressed and
+    /// the errors sent through the stream never fail the scrape. A streamed
+    /// scrape holds its `max_concurrent_scrapes` slot until the stream ends.
+    ///
+    /// [`MetricsSender`]: struct.MetricsSender.html
+    pub fn streaming(buffer: usize) -> (Self, MetricsSender) {
+        let (tx, rx) = mpsc::channel(buffer);
+
+        (
+            Self {
+                stream: Some(rx),
+                ..Self::default()
+            },
+            MetricsSender { tx },
+        )
+    }
+
+    /// Appends rendered metrics to the collection.
+    pub fn push_metrics(&mut self, rendered: &str) {
+        self.metrics.push_str(rendered);
+    }
+
+    /// Records an error without discarding the metrics collected so far.
+    pub fn push_error<E>(&mut self, err: E)
+    where
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        self.errors.push(err.into());
+    }
+
+    /// Keeps the collection slot `permit` until the end of the stream so
+    /// streamed scrapes count against `max_concurrent_scrapes` too. A
+    /// collection without a stream is over already, the slot is released.
+    pub(crate) fn hold_while_streaming(&mut self, permit: Option<OwnedSemaphorePermit>) {
+        if self.stream.is_some() {
+            self.permit = permit;
+        }
+    }
+
+    /// Waits for the end of the stream, if any, moving its
+    /// content in `metrics` and `errors`.
+    pub(crate) async fn drain(&mut self) {
+        if let Some(mut stream) = self.stream.take() {
+            while let Some(item) = stream.recv().await {
+                match item {
+                    StreamItem::Metrics(rendered) => self.push_metrics(&rendered),
+                    StreamItem::Error(err) => self.push_error(err),
+                }
+            }
+        }
+        self.permit = None;
+    }
+
+    /// Renders the metrics followed by the `exporter_scrape_errors` gauge.
+    /// The stream, if any, is ignored.
+    pub(crate) fn render(&self) -> String {
+        let mut s = self.metrics.clone();
+        s.push_str(&render_scrape_errors(self.errors.len()));
+        s
+    }
+
+    /// Renders the collection, streaming it if possible, followed by `trailer`.
+    pub(crate) fn into_body(self, trailer: String) -> Body {
+        let (metrics, mut errors, mut stream, permit) = match self {
+            Collection {
+                metrics,
+                errors,
+                stream: Some(stream),
+                permit,
+            } => (metrics, errors.len(), stream, permit),
+            collection => return Body::from(collection.render() + &trailer),
+        };
+
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            if !metrics.is_empty() && sender.send_data(metrics.into()).await.is_err() {
+                return;
+            }
+
+            while let Some(item) = stream.recv().await {
+                match item {
+                    StreamItem::Metrics(rendered) => {
+                        // dropping the stream tells the collector to stop
+                        if sender.send_data(rendered.into()).await.is_err() {
+                            return;
+                        }
+                    }
+                    StreamItem::Error(err) => {
+                        warn!("collection error == {:?}", err);
+                        errors += 1;
+                    }
+                }
+            }
+
+            // the collection is over, the early returns release the slot too
+            drop(permit);
+
+            let _ = sender
+                .send_data((render_scrape_errors(errors) + &trailer).into())
+                .await;
+        });
+
+        body
+    }
+}
+
+fn render_scrape_errors(errors: usize) -> String {
+    PrometheusMetric::build()
+        .with_name("exporter_scrape_errors")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("Number of errors met while collecting the metrics of this scrape")
+        .build()
+        .render_and_append_instance(&PrometheusInstance::new().with_value(errors))
+        .render()
+}
+
+impl From<String> for Collection {
+    fn from(metrics: String) -> Self {
+        Self {
+            metrics,
+            ..Self::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut collection = Collection::from("up 1\n".to_owned());
+        collection.push_error("backend down");
+        collection.push_metrics("other 2\n");
+
+        assert_eq!(
+            collection.render(),
+            "up 1\n\
+            other 2\n\
+            # HELP exporter_scrape_errors Number of errors met while collecting the metrics of this scrape\n\
+            # TYPE exporter_scrape_errors gauge\n\
+            exporter_scrape_errors 1\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_streaming() {
+        let (collection, sender) = Collection::streaming(1);
+        let producer = tokio::spawn(async move {
+            for family in &["a 1\n", "b 2\n", "c 3\n"] {
+                sender.send((*family).to_owned()).await?;
+            }
+            sender.send_error("backend down").await
+        });
+
+        let body = hyper::body::to_bytes(collection.into_body("trailer 1\n".to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(
+            body,
+            "a 1\n\
+            b 2\n\
+            c 3\n\
+            # HELP exporter_scrape_errors Number of errors met while collecting the metrics of this scrape\n\
+            # TYPE exporter_scrape_errors gauge\n\
+            exporter_scrape_errors 1\n\
+            trailer 1\n"
+        );
+        assert_eq!(producer.await.unwrap(), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let (mut collection, sender) = Collection::streaming(4);
+        collection.push_metrics("a 1\n");
+        sender.send("b 2\n".to_owned()).await.unwrap();
+        sender.send_error("backend down").await.unwrap();
+        drop(sender);
+
+        collection.drain().await;
+        assert_eq!(collection.metrics, "a 1\nb 2\n");
+        assert_eq!(collection.errors.len(), 1);
+        assert!(collection.stream.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_aborted() {
+        let (collection, sender) = Collection::streaming(1);
+        drop(collection);
+
+        assert_eq!(sender.send("a 1\n".to_owned()).await, Err(ScrapeAborted {}));
+    }
+}
@@ -0,0 +1,680 @@
+use crate::catch_panic::catch_collector_panic;
+use crate::{Collection, MetricType, PrometheusInstance, PrometheusMetric, ScrapeContext};
+use async_trait::async_trait;
+use futures_util::future::join_all;
+use log::warn;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// A source of metrics. Implement it for each of your sub-collectors
+/// and register them in a [`CollectorRegistry`].
+///
+/// [`CollectorRegistry`]: struct.CollectorRegistry.html
+///
+/// # Examples
+///
+/// ```
+/// use prometheus_exporter_base::prelude::*;
+/// use std::error::Error;
+///
+/// struct Uptime {}
+///
+/// #[async_trait]
+/// impl Collector for Uptime {
+///     async fn collect(
+///         &self,
+///         _context: &ScrapeContext,
+///     ) -> Result<String, Box<dyn Error + Send + Sync>> {
+///         Ok(PrometheusMetric::build()
+///             .with_name("uptime_seconds")
+///             .with_metric_type(MetricType::Counter)
+///             .with_help("Seconds since boot")
+///             .build()
+///             .render_and_append_instance(&PrometheusInstance::new().with_value(42))
+///             .render())
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait Collector: Send + Sync {
+    /// Returns the rendered metrics of this collector.
+    async fn collect(
+        &self,
+        context: &ScrapeContext,
+    ) -> Result<String, Box<dyn Error + Send + Sync>>;
+}
+
+struct RegisteredCollector {
+    name: String,
+    collector: Arc<dyn Collector>,
+    timeout: Option<Duration>,
+    enabled: bool,
+}
+
+/// Returned when a collector name passed in the configuration
+/// or in the `collect[]` query parameter cannot be used.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum CollectorSelectionError {
+    #[error("unknown collector {name}, available collectors: {available}")]
+    Unknown { name: String, available: String },
+    #[error("collector {name} is disabled, enabled collectors: {enabled}")]
+    Disabled { name: String, enabled: String },
+}
+
+/// Runs many named [`Collector`]s concurrently, each one isolated from
+/// the others: a collector failing or timing out only loses its own metrics.
+/// The outcome of each collector is reported with the `collector_success`
+/// and `collector_duration_seconds` gauges.
+///
+/// Collectors can be disabled by default and toggled by configuration
+/// with [`with_enabled`]. A scrape can further restrict the enabled
+/// collectors it wants with the `collect[]` query parameter, for example
+/// `/metrics?collect[]=cpu&collect[]=disk`.
+///
+/// [`with_enabled`]: struct.CollectorRegistry.html#method.with_enabled
+///
+/// [`Collector`]: trait.Collector.html
+pub struct CollectorRegistry {
+    collectors: Vec<RegisteredCollector>,
+    default_timeout: Duration,
+}
+
+impl std::fmt::Debug for CollectorRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("CollectorRegistry")
+            .field(
+                "collectors",
+                &self
+                    .collectors
+                    .iter()
+                    .map(|registered| registered.name.as_str())
+                    .collect::<Vec<_>>(),
+            )
+            .field("default_timeout", &self.default_timeout)
+            .finish()
+    }
+}
+
+impl Default for CollectorRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CollectorRegistry {
+    /// Creates an empty registry. Collectors time out after 10 seconds
+    /// or when the scrape deadline expires, whichever comes first.
+    pub fn new() -> Self {
+        Self {
+            collectors: Vec::new(),
+            default_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Sets the timeout of the collectors registered without one.
+    pub fn with_default_timeout(self, default_timeout: Duration) -> Self {
+        Self {
+            default_timeout,
+            ..self
+        }
+    }
+
+    /// Registers `collector` under `name`, used as the `collector` label value.
+    /// The collector is enabled.
+    ///
+    /// # Panics
+    ///
+    /// If a collector is already registered under `name`.
+    pub fn with_collector<C>(self, name: &str, collector: C) -> Self
+    where
+        C: Collector + 'static,
+    {
+        self.register(name, collector, None, true)
+    }
+
+    /// Registers `collector` under `name` with its own timeout. The
+    /// collector is enabled.
+    ///
+    /// # Panics
+    ///
+    /// If a collector is already registered under `name`.
+    pub fn with_collector_timeout<C>(self, name: &str, collector: C, timeout: Duration) -> Self
+    where
+        C: Collector + 'static,
+    {
+        self.register(name, collector, Some(timeout), true)
+    }
+
+    /// Registers `collector` under `name` but disabled: it will run only
+    /// if enabled with [`with_enabled`].
+    ///
+    /// # Panics
+    ///
+    /// If a collector is already registered under `name`.
+    ///
+    /// [`with_enabled`]: struct.CollectorRegistry.html#method.with_enabled
+    pub fn with_disabled_collector<C>(self, name: &str, collector: C) -> Self
+    where
+        C: Collector + 'static,
+    {
+        self.register(name, collector, None, false)
+    }
+
+    fn register<C>(self, name: &str, collector: C, timeout: Option<Duration>, enabled: bool) -> Self
+    where
+        C: Collector + 'static,
+    {
+        // the name is the label telling the collectors apart
+        assert!(
+            self.collectors
+                .iter()
+                .all(|registered| registered.name != name),
+            "a collector is already registered as {:?}",
+            name
+        );
+
+        let mut collectors = self.collectors;
+        collectors.push(RegisteredCollector {
+            name: name.to_owned(),
+            collector: Arc::new(collector),
+            timeout,
+            enabled,
+        });
+
+        Self { collectors, ..self }
+    }
+
+    /// Enables or disables the collector registered as `name`, for example
+    /// from a command line flag.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use prometheus_exporter_base::prelude::*;
+    /// use std::error::Error;
+    ///
+    /// struct Noop {}
+    ///
+    /// #[async_trait]
+    /// impl Collector for Noop {
+    ///     async fn collect(
+    ///         &self,
+    ///         _context: &ScrapeContext,
+    ///     ) -> Result<String, Box<dyn Error + Send + Sync>> {
+    ///         Ok(String::new())
+    ///     }
+    /// }
+    ///
+    /// let registry = CollectorRegistry::new()
+    ///     .with_collector("cpu", Noop {})
+    ///     .with_disabled_collector("wifi", Noop {})
+    ///     .with_enabled("cpu", false)
+    ///     .and_then(|registry| registry.with_enabled("wifi", true))
+    ///     .expect("unknown collector");
+    ///
+    /// assert!(registry.with_enabled("gpu", true).is_err());
+    /// ```
+    pub fn with_enabled(self, name: &str, enabled: bool) -> Result<Self, CollectorSelectionError> {
+        let mut collectors = self.collectors;
+        match collectors
+            .iter_mut()
+            .find(|registered| registered.name == name)
+        {
+            Some(registered) => registered.enabled = enabled,
+            None => {
+                return Err(CollectorSelectionError::Unknown {
+                    name: name.to_owned(),
+                    available: join_names(collectors.iter()),
+                })
+            }
+        }
+
+        Ok(Self { collectors, ..self })
+    }
+
+    /// The names of the registered collectors, in registration order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.collectors
+            .iter()
+            .map(|registered| registered.name.as_str())
+    }
+
+    /// Returns the collectors to run for the scrape.
+    fn select(
+        &self,
+        context: &ScrapeContext,
+    ) -> Result<Vec<&RegisteredCollector>, CollectorSelectionError> {
+        let requested = context.query_params("collect[]").collect::<Vec<_>>();
+        if requested.is_empty() {
+            return Ok(self
+                .collectors
+                .iter()
+                .filter(|registered| registered.enabled)
+                .collect());
+        }
+
+        for name in &requested {
+            match self
+                .collectors
+                .iter()
+                .find(|registered| registered.name == *name)
+            {
+                Some(registered) if registered.enabled => {}
+                Some(_) => {
+                    return Err(CollectorSelectionError::Disabled {
+                        name: (*name).to_owned(),
+                        enabled: join_names(
+                            self.collectors
+                                .iter()
+                                .filter(|registered| registered.enabled),
+                        ),
+                    })
+                }
+                None => {
+                    return Err(CollectorSelectionError::Unknown {
+                        name: (*name).to_owned(),
+                        available: join_names(self.collectors.iter()),
+                    })
+                }
+            }
+        }
+
+        Ok(self
+            .collectors
+            .iter()
+            .filter(|registered| requested.contains(&registered.name.as_str()))
+            .collect())
+    }
+
+    /// Runs the selected collectors concurrently and renders their metrics
+    /// followed by the outcome of each one. Failing collectors are reported
+    /// as errors of the returned [`Collection`].
+    ///
+    /// [`Collection`]: struct.Collection.html
+    pub async fn collect_all(
+        &self,
+        context: &ScrapeContext,
+    ) -> Result<Collection, CollectorSelectionError> {
+        let selected = self.select(context)?;
+
+        let outcomes = join_all(selected.iter().map(|registered| async move {
+            let mut timeout = registered.timeout.unwrap_or(self.default_timeout);
+            if let Some(time_left) = context.time_left() {
+                timeout = timeout.min(time_left);
+            }
+
+            let started = Instant::now();
+            // a panicking collector fails alone, like a failing one
+            let collect =
+                catch_collector_panic(&registered.name, || registered.collector.collect(context));
+            let outcome = match tokio::time::timeout(timeout, collect).await {
+                Ok(Ok(rendered)) => Ok(rendered),
+                Ok(Err(err)) => Err(format!("collector {} failed: {}", registered.name, err)),
+                Err(_) => Err(format!(
+                    "collector {} timed out after {:?}",
+                    registered.name, timeout
+                )),
+            };
+
+            (registered.name.as_str(), outcome, started.elapsed())
+        }))
+        .await;
+
+        let mut collection = Collection::new();
+        let mut success = PrometheusMetric::build()
+            .with_name("collector_success")
+            .with_metric_type(MetricType::Gauge)
+            .with_help("Whether the collector succeeded")
+            .build();
+        let mut duration = PrometheusMetric::build()
+            .with_name("collector_duration_seconds")
+            .with_metric_type(MetricType::Gauge)
+            .with_help("How long the collector took to complete in seconds")
+            .build();
+
+        for (name, outcome, elapsed) in outcomes {
+            success.render_and_append_instance(
+                &PrometheusInstance::new()
+                    .with_label("collector", name)
+                    .with_value(outcome.is_ok() as u8),
+            );
+            duration.render_and_append_instance(
+                &PrometheusInstance::new()
+                    .with_label("collector", name)
+                    .with_value(elapsed.as_secs_f64()),
+            );
+
+            match outcome {
+                Ok(rendered) => collection.push_metrics(&rendered),
+                Err(err) => collection.push_error(err),
+            }
+        }
+
+        if !selected.is_empty() {
+            collection.push_metrics(&success.render());
+            collection.push_metrics(&duration.render());
+        }
+
+        Ok(collection)
+    }
+}
+
+fn join_names<'a>(collectors: impl Iterator<Item = &'a RegisteredCollector>) -> String {
+    collectors
+        .map(|registered| registered.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[async_trait]
+impl Collector for CollectorRegistry {
+    async fn collect(
+        &self,
+        context: &ScrapeContext,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let collection = self.collect_all(context).await?;
+        for err in &collection.errors {
+            warn!("{}", err);
+        }
+
+        Ok(collection.metrics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixed(&'static str);
+
+    #[async_trait]
+    impl Collector for Fixed {
+        async fn collect(
+            &self,
+            _context: &ScrapeContext,
+        ) -> Result<String, Box<dyn Error + Send + Sync>> {
+            Ok(self.0.to_owned())
+        }
+    }
+
+    struct Failing {}
+
+    #[async_trait]
+    impl Collector for Failing {
+        async fn collect(
+            &self,
+            _context: &ScrapeContext,
+        ) -> Result<String, Box<dyn Error + Send + Sync>> {
+            Err("backend down".into())
+        }
+    }
+
+    struct Panicking {}
+
+    #[async_trait]
+    impl Collector for Panicking {
+        async fn collect(
+            &self,
+            _context: &ScrapeContext,
+        ) -> Result<String, Box<dyn Error + Send + Sync>> {
+            let size: Result<u64, std::io::Error> =
+                std::fs::metadata("/nonexistent").map(|m| m.len());
+            Ok(format!("size {}\n", size.expect("cannot read the size")))
+        }
+    }
+
+    struct Slow {}
+
+    #[async_trait]
+    impl Collector for Slow {
+        async fn collect(
+            &self,
+            _context: &ScrapeContext,
+        ) -> Result<String, Box<dyn Error + Send + Sync>> {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok("slow 1\n".to_owned())
+        }
+    }
+
+    fn success_lines(rendered: &str) -> Vec<&str> {
+        rendered
+            .lines()
+            .filter(|line| line.starts_with("collector_success{"))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_isolation() {
+        let registry = CollectorRegistry::new()
+            .with_collector("cpu", Fixed("cpu 1\n"))
+            .with_collector("disk", Failing {})
+            .with_collector_timeout("net", Slow {}, Duration::from_millis(50))
+            .with_collector("mem", Fixed("mem 2\n"));
+
+        let collection = registry
+            .collect_all(&ScrapeContext::default())
+            .await
+            .unwrap();
+        let rendered = collection.metrics;
+
+        assert!(rendered.starts_with(
+            "cpu 1\nmem 2\n# HELP collector_success Whether the collector succeeded\n"
+        ));
+        assert!(!rendered.contains("slow 1"));
+        assert_eq!(
+            success_lines(&rendered),
+            vec![
+                "collector_success{collector=\"cpu\"} 1",
+                "collector_success{collector=\"disk\"} 0",
+                "collector_success{collector=\"net\"} 0",
+                "collector_success{collector=\"mem\"} 1",
+            ]
+        );
+        assert_eq!(
+            rendered
+                .lines()
+                .filter(|line| line.starts_with("collector_duration_seconds{"))
+                .count(),
+            4
+        );
+        assert_eq!(
+            collection
+                .errors
+                .iter()
+                .map(|err| err.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "collector disk failed: backend down",
+                "collector net timed out after 50ms",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_panic() {
+        let registry = CollectorRegistry::new()
+            .with_collector("cpu", Fixed("cpu 1\n"))
+            .with_collector("size", Panicking {});
+
+        let collection = registry
+            .collect_all(&ScrapeContext::default())
+            .await
+            .unwrap();
+
+        assert!(collection.metrics.starts_with("cpu 1\n"));
+        assert_eq!(
+            success_lines(&collection.metrics),
+            vec![
+                "collector_success{collector=\"cpu\"} 1",
+                "collector_success{collector=\"size\"} 0",
+            ]
+        );
+        assert_eq!(
+            collection.errors[0].to_string(),
+            "collector size failed: the collector panicked"
+        );
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_spans() {
+        use std::collections::HashMap;
+        use std::sync::Mutex;
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+        /// The `collector` and `outcome` fields of the `collect` spans.
+        #[derive(Clone, Default)]
+        struct Spans(Arc<Mutex<HashMap<u64, HashMap<&'static str, String>>>>);
+
+        struct Fields<'a>(&'a mut HashMap<&'static str, String>);
+
+        impl Visit for Fields<'_> {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                self.0.insert(field.name(), format!("{:?}", value));
+            }
+
+            fn record_str(&mut self, field: &Field, value: &str) {
+                self.0.insert(field.name(), value.to_owned());
+            }
+        }
+
+        impl<S: tracing::Subscriber> Layer<S> for Spans {
+            fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, _: Context<'_, S>) {
+                if attrs.metadata().name() == "collect" {
+                    let mut fields = HashMap::new();
+                    attrs.record(&mut Fields(&mut fields));
+                    self.0.lock().unwrap().insert(id.into_u64(), fields);
+                }
+            }
+
+            fn on_record(&self, id: &Id, values: &Record<'_>, _: Context<'_, S>) {
+                if let Some(fields) = self.0.lock().unwrap().get_mut(&id.into_u64()) {
+                    values.record(&mut Fields(fields));
+                }
+            }
+        }
+
+        let spans = Spans::default();
+        let _default =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(spans.clone()));
+        CollectorRegistry::new()
+            .with_collector("cpu", Fixed("cpu 1\n"))
+            .with_collector("size", Panicking {})
+            .collect_all(&ScrapeContext::default())
+            .await
+            .unwrap();
+
+        let mut recorded = spans
+            .0
+            .lock()
+            .unwrap()
+            .values()
+            .map(|fields| (fields["collector"].clone(), fields["outcome"].clone()))
+            .collect::<Vec<_>>();
+        recorded.sort();
+        assert_eq!(
+            recorded,
+            vec![
+                ("cpu".to_owned(), "ok".to_owned()),
+                ("size".to_owned(), "panicked".to_owned()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scrape_deadline() {
+        let registry = CollectorRegistry::new().with_collector("net", Slow {});
+        let context = ScrapeContext {
+            deadline: Some(Instant::now() + Duration::from_millis(50)),
+            ..ScrapeContext::default()
+        };
+
+        let started = Instant::now();
+        let rendered = registry.collect_all(&context).await.unwrap().metrics;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            success_lines(&rendered),
+            vec!["collector_success{collector=\"net\"} 0"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_empty() {
+        let collection = CollectorRegistry::new()
+            .collect_all(&ScrapeContext::default())
+            .await
+            .unwrap();
+        assert_eq!(collection.metrics, "");
+        assert!(collection.errors.is_empty());
+    }
+
+    fn collect_query(names: &[&str]) -> ScrapeContext {
+        ScrapeContext {
+            query: names
+                .iter()
+                .map(|name| ("collect[]".to_owned(), (*name).to_owned()))
+                .collect(),
+            ..ScrapeContext::default()
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "a collector is already registered as \"cpu\"")]
+    fn test_duplicate_name() {
+        let _ = CollectorRegistry::new()
+            .with_collector("cpu", Fixed("cpu 1\n"))
+            .with_disabled_collector("cpu", Fixed("cpu 2\n"));
+    }
+
+    #[tokio::test]
+    async fn test_selection() {
+        let registry = CollectorRegistry::new()
+            .with_collector("cpu", Fixed("cpu 1\n"))
+            .with_collector("disk", Fixed("disk 1\n"))
+            .with_disabled_collector("wifi", Fixed("wifi 1\n"))
+            .with_collector("mem", Fixed("mem 1\n"))
+            .with_enabled("mem", false)
+            .unwrap();
+
+        let rendered = registry
+            .collect_all(&ScrapeContext::default())
+            .await
+            .unwrap()
+            .metrics;
+        assert!(rendered.starts_with("cpu 1\ndisk 1\n# HELP"));
+
+        let rendered = registry
+            .collect_all(&collect_query(&["disk"]))
+            .await
+            .unwrap()
+            .metrics;
+        assert!(rendered.starts_with("disk 1\n# HELP"));
+        assert_eq!(
+            success_lines(&rendered),
+            vec!["collector_success{collector=\"disk\"} 1"]
+        );
+
+        assert_eq!(
+            registry
+                .collect_all(&collect_query(&["cpu", "gpu"]))
+                .await
+                .unwrap_err(),
+            CollectorSelectionError::Unknown {
+                name: "gpu".to_owned(),
+                available: "cpu, disk, wifi, mem".to_owned(),
+            }
+        );
+        assert_eq!(
+            registry
+                .collect_all(&collect_query(&["wifi"]))
+                .await
+                .unwrap_err()
+                .to_string(),
+            "collector wifi is disabled, enabled collectors: cpu, disk"
+        );
+    }
+}
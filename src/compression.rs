@@ -0,0 +1,148 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{self, Write};
+
+/// The content codings the server can compress a response with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Identity,
+    Gzip,
+    #[cfg(feature = "deflate")]
+    Deflate,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// The supported encodings, the preferred first.
+const SUPPORTED: &[Encoding] = &[
+    #[cfg(feature = "zstd")]
+    Encoding::Zstd,
+    Encoding::Gzip,
+    #[cfg(feature = "deflate")]
+    Encoding::Deflate,
+];
+
+impl Encoding {
+    /// The value of the `Content-Encoding` header, `None` for identity.
+    pub(crate) fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Identity => None,
+            Encoding::Gzip => Some("gzip"),
+            #[cfg(feature = "deflate")]
+            Encoding::Deflate => Some("deflate"),
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => Some("zstd"),
+        }
+    }
+
+    /// Picks the encoding given the `Accept-Encoding` header of the
+    /// request: the one with the highest quality, ties broken by
+    /// preferring zstd, then gzip, then deflate.
+    pub(crate) fn negotiate(accept_encoding: Option<&str>) -> Self {
+        let mut qualities = Vec::new();
+        let mut wildcard = None;
+
+        for coding in accept_encoding.unwrap_or_default().split(',') {
+            let mut parameters = coding.split(';').map(str::trim);
+            let name = parameters.next().unwrap_or_default().to_ascii_lowercase();
+            let quality = parameters
+                .filter_map(|parameter| parameter.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            if name == "*" {
+                wildcard = Some(quality);
+            } else {
+                qualities.push((name, quality));
+            }
+        }
+
+        let mut best = (Encoding::Identity, 0.0);
+        for encoding in SUPPORTED {
+            let quality = qualities
+                .iter()
+                .find(|(name, _)| Some(name.as_str()) == encoding.content_encoding())
+                .map(|(_, quality)| *quality)
+                .or(wildcard)
+                .unwrap_or(0.0);
+
+            if quality > best.1 {
+                best = (*encoding, quality);
+            }
+        }
+
+        best.0
+    }
+
+    pub(crate) fn encode(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Identity => Ok(body.to_vec()),
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            #[cfg(feature = "deflate")]
+            Encoding::Deflate => {
+                // HTTP deflate is the zlib format, not raw deflate
+                let mut encoder =
+                    flate2::write::ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => zstd::encode_all(body, 0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Encoding::negotiate(None), Encoding::Identity);
+        assert_eq!(Encoding::negotiate(Some("br")), Encoding::Identity);
+        assert_eq!(Encoding::negotiate(Some("gzip")), Encoding::Gzip);
+        assert_eq!(Encoding::negotiate(Some("GZIP;q=0.5, br")), Encoding::Gzip);
+        assert_eq!(Encoding::negotiate(Some("gzip;q=0")), Encoding::Identity);
+        assert_eq!(Encoding::negotiate(Some("*;q=0")), Encoding::Identity);
+        assert_eq!(
+            Encoding::negotiate(Some("*, gzip;q=0")),
+            SUPPORTED
+                .iter()
+                .copied()
+                .find(|encoding| *encoding != Encoding::Gzip)
+                .unwrap_or(Encoding::Identity)
+        );
+    }
+
+    #[cfg(all(feature = "deflate", feature = "zstd"))]
+    #[test]
+    fn test_negotiate_preference() {
+        assert_eq!(
+            Encoding::negotiate(Some("deflate, gzip, zstd")),
+            Encoding::Zstd
+        );
+        assert_eq!(
+            Encoding::negotiate(Some("deflate, gzip;q=0.9, zstd;q=0.1")),
+            Encoding::Deflate
+        );
+    }
+
+    #[test]
+    fn test_gzip() {
+        let body = "pippo 1\n".repeat(1000);
+        let encoded = Encoding::Gzip.encode(body.as_bytes()).unwrap();
+        assert!(encoded.len() < body.len());
+
+        let mut decoded = String::new();
+        GzDecoder::new(encoded.as_slice())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+    }
+}
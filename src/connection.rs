@@ -0,0 +1,355 @@
+use crate::{HttpVersions, ServerOptions};
+use futures_util::task::AtomicWaker;
+use hyper::server::accept::Accept;
+use hyper::server::Builder;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Instant, Sleep};
+
+/// hyper refuses buffers smaller than this.
+const MIN_MAX_HEADER_SIZE: usize = 8192;
+
+/// Applies the connection limits of the options to the server.
+pub(crate) fn server_builder<I>(
+    incoming: I,
+    server_options: &ServerOptions,
+) -> Builder<IdleIncoming<I>> {
+    let mut builder = hyper::Server::builder(IdleIncoming {
+        inner: incoming,
+        idle_timeout: server_options.idle_timeout,
+    })
+    .http1_keepalive(server_options.keep_alive)
+    .http1_max_buf_size(server_options.max_header_size.max(MIN_MAX_HEADER_SIZE));
+    match server_options.http_versions {
+        HttpVersions::Http1 => builder = builder.http1_only(true),
+        HttpVersions::Http2 => builder = builder.http2_only(true),
+        HttpVersions::Http1AndHttp2 => {}
+    }
+    if let Some(header_read_timeout) = server_options.header_read_timeout {
+        builder = builder.http1_header_read_timeout(header_read_timeout);
+    }
+
+    builder
+}
+
+/// Wraps the accepted connections in [`IdleTimeout`].
+pub(crate) struct IdleIncoming<I> {
+    inner: I,
+    idle_timeout: Option<Duration>,
+}
+
+impl<I> Accept for IdleIncoming<I>
+where
+    I: Accept + Unpin,
+{
+    type Conn = IdleTimeout<I::Conn>;
+    type Error = I::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let idle_timeout = self.idle_timeout;
+        Pin::new(&mut self.inner)
+            .poll_accept(cx)
+            .map_ok(|conn| IdleTimeout::new(conn, idle_timeout))
+    }
+}
+
+/// A connection closed once it has been idle for too long: no
+/// request being served and no byte read or written. It covers both the
+/// clients connecting without sending anything and the idle keep-alive
+/// connections, which hyper would keep open forever.
+pub(crate) struct IdleTimeout<S> {
+    inner: S,
+    idle_timeout: Option<Duration>,
+    deadline: Option<Pin<Box<Sleep>>>,
+    in_flight: Arc<InFlightState>,
+}
+
+#[derive(Default)]
+struct InFlightState {
+    requests: AtomicUsize,
+    /// Woken once no request is left, hyper does not read the
+    /// connection again by itself after answering.
+    idle: AtomicWaker,
+}
+
+impl<S> IdleTimeout<S> {
+    fn new(inner: S, idle_timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            idle_timeout,
+            deadline: idle_timeout.map(|idle_timeout| Box::pin(sleep(idle_timeout))),
+            in_flight: Arc::new(InFlightState::default()),
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Marks a request of the connection as being served until the
+    /// returned guard is dropped.
+    pub(crate) fn in_flight(&self) -> InFlight {
+        InFlight {
+            in_flight: self.in_flight.clone(),
+        }
+    }
+
+    fn postpone_deadline(&mut self) {
+        if let (Some(deadline), Some(idle_timeout)) = (&mut self.deadline, self.idle_timeout) {
+            deadline.as_mut().reset(Instant::now() + idle_timeout);
+        }
+    }
+}
+
+/// Counts the requests being served on a connection.
+#[derive(Clone)]
+pub(crate) struct InFlight {
+    in_flight: Arc<InFlightState>,
+}
+
+impl InFlight {
+    pub(crate) fn enter(&self) -> InFlightGuard {
+        self.in_flight.requests.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard {
+            in_flight: self.in_flight.clone(),
+        }
+    }
+}
+
+pub(crate) struct InFlightGuard {
+    in_flight: Arc<InFlightState>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.in_flight.requests.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.in_flight.idle.wake();
+        }
+    }
+}
+
+impl<S> AsyncRead for IdleTimeout<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                self.postpone_deadline();
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                // registered first so a request ending right after the
+                // check still wakes us
+                self.in_flight.idle.register(cx.waker());
+                if self.in_flight.requests.load(Ordering::SeqCst) > 0 {
+                    self.postpone_deadline();
+                    return Poll::Pending;
+                }
+
+                let timed_out = match &mut self.deadline {
+                    Some(deadline) => deadline.as_mut().poll(cx).is_ready(),
+                    None => false,
+                };
+                if timed_out {
+                    Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "idle connection timed out",
+                    )))
+                } else {
+                    Poll::Pending
+                }
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for IdleTimeout<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if written.is_ready() {
+            self.postpone_deadline();
+        }
+        written
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let written = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        if written.is_ready() {
+            self.postpone_deadline();
+        }
+        written
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Authorization;
+    use hyper::server::conn::{AddrIncoming, AddrStream};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response};
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    /// Starts a server answering "ok" after `delay`.
+    fn start(server_options: &ServerOptions, delay: Duration) -> SocketAddr {
+        let incoming = AddrIncoming::bind(&([127, 0, 0, 1], 0).into()).unwrap();
+        let addr = incoming.local_addr();
+
+        let server = server_builder(incoming, server_options).serve(make_service_fn(
+            move |conn: &IdleTimeout<AddrStream>| {
+                let in_flight = conn.in_flight();
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |_req| {
+                        let guard = in_flight.enter();
+                        async move {
+                            tokio::time::sleep(delay).await;
+                            drop(guard);
+                            Ok::<_, hyper::Error>(Response::new(Body::from("ok")))
+                        }
+                    }))
+                }
+            },
+        ));
+        tokio::spawn(server);
+
+        addr
+    }
+
+    /// Reads until the server closes the connection.
+    async fn read_to_close(stream: &mut TcpStream) -> String {
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    fn server_options() -> ServerOptions {
+        ServerOptions::new(([127, 0, 0, 1], 0).into(), Authorization::None)
+            .with_idle_timeout(Some(Duration::from_millis(300)))
+            .with_header_read_timeout(Some(Duration::from_millis(300)))
+    }
+
+    #[tokio::test]
+    async fn test_silent_client() {
+        let addr = start(&server_options(), Duration::ZERO);
+
+        let started = std::time::Instant::now();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(read_to_close(&mut stream).await, "");
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_slow_headers() {
+        let addr = start(&server_options(), Duration::ZERO);
+
+        let started = std::time::Instant::now();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        // the headers trickle in but never end
+        for _ in 0..3 {
+            stream
+                .write_all(b"GET /metrics HTTP/1.1\r\n")
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(150)).await;
+        }
+        assert!(!read_to_close(&mut stream).await.contains("200 OK"));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_slow_collector() {
+        // serving the request does not count as idle time
+        let addr = start(&server_options(), Duration::from_millis(600));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nhost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_to_close(&mut stream).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("ok"));
+    }
+
+    #[tokio::test]
+    async fn test_http_versions() {
+        let get = |addr: SocketAddr, http2: bool| async move {
+            let client = hyper::Client::builder()
+                .http2_only(http2)
+                .build_http::<Body>();
+            let uri = format!("http://{}/metrics", addr).parse().unwrap();
+            client.get(uri).await.map(|response| response.version())
+        };
+
+        let http1 = start(&server_options(), Duration::ZERO);
+        assert_eq!(get(http1, false).await.unwrap(), hyper::Version::HTTP_11);
+        assert!(get(http1, true).await.is_err());
+
+        let http2 = start(
+            &server_options().with_http_versions(HttpVersions::Http2),
+            Duration::ZERO,
+        );
+        assert_eq!(get(http2, true).await.unwrap(), hyper::Version::HTTP_2);
+        assert!(get(http2, false).await.is_err());
+
+        let both = start(
+            &server_options().with_http_versions(HttpVersions::Http1AndHttp2),
+            Duration::ZERO,
+        );
+        assert_eq!(get(both, false).await.unwrap(), hyper::Version::HTTP_11);
+        assert_eq!(get(both, true).await.unwrap(), hyper::Version::HTTP_2);
+    }
+
+    #[tokio::test]
+    async fn test_large_headers() {
+        let addr = start(&server_options().with_max_header_size(8192), Duration::ZERO);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "GET /metrics HTTP/1.1\r\nhost: localhost\r\nx-padding: {}\r\n\r\n",
+            "a".repeat(16 * 1024)
+        );
+        // the server may close before reading everything
+        let _ = stream.write_all(request.as_bytes()).await;
+        assert!(!read_to_close(&mut stream).await.contains("200 OK"));
+    }
+}
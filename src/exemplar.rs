@@ -0,0 +1,145 @@
+use thiserror::Error;
+
+/// OpenMetrics caps the label names and values of an exemplar, all
+/// together, to this many characters.
+pub const MAX_EXEMPLAR_LABELS_LENGTH: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error(
+    "the exemplar labels are {0} characters long, more than the {} allowed",
+    MAX_EXEMPLAR_LABELS_LENGTH
+)]
+pub struct ExemplarTooLong(usize);
+
+impl ExemplarTooLong {
+    /// The length of the label names and values of the refused exemplar.
+    pub fn length(&self) -> usize {
+        self.0
+    }
+}
+
+/// A sample taken from the observations of a counter or a histogram
+/// bucket, usually carrying the trace it comes from. Exemplars are only
+/// rendered in the OpenMetrics format.
+///
+/// Example:
+///
+/// ```
+/// use prometheus_exporter_base::prelude::*;
+///
+/// let exemplar = Exemplar::new(0.67)
+///     .with_label("trace_id", "4bf92f3577b34da6a3ce929d0e0e4736")
+///     .with_timestamp(1520879607.789);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exemplar<'a> {
+    labels: Vec<(&'a str, &'a str)>,
+    value: f64,
+    timestamp: Option<f64>,
+}
+
+impl<'a> Exemplar<'a> {
+    pub fn new(value: f64) -> Self {
+        Self {
+            labels: Vec::new(),
+            value,
+            timestamp: None,
+        }
+    }
+
+    pub fn with_label(self, l: &'a str, v: &'a str) -> Self {
+        let mut labels = self.labels;
+        labels.push((l, v));
+
+        Self { labels, ..self }
+    }
+
+    /// Sets when the exemplar was observed, in seconds since the
+    /// `UNIX_EPOCH`.
+    pub fn with_timestamp(self, timestamp: f64) -> Self {
+        Self {
+            timestamp: Some(timestamp),
+            ..self
+        }
+    }
+
+    /// Fails if the labels are too long for OpenMetrics.
+    pub(crate) fn validate(&self) -> Result<(), ExemplarTooLong> {
+        let length = self
+            .labels
+            .iter()
+            .map(|(l, v)| l.chars().count() + v.chars().count())
+            .sum();
+        if length > MAX_EXEMPLAR_LABELS_LENGTH {
+            Err(ExemplarTooLong(length))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Renders the exemplar as it follows the sample, including the
+    /// leading ` # `.
+    pub(crate) fn render(&self) -> String {
+        let labels = self
+            .labels
+            .iter()
+            .map(|(l, v)| format!("{}=\"{}\"", l, v))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let mut s = format!(" # {{{}}} {}", labels, render_float(self.value));
+        if let Some(timestamp) = self.timestamp {
+            s.push(' ');
+            s.push_str(&render_float(timestamp));
+        }
+
+        s
+    }
+}
+
+/// Formats floats the OpenMetrics way, always with a decimal point.
+fn render_float(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_owned()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_owned()
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            Exemplar::new(1.0)
+                .with_label("trace_id", "KOO5S4vxi0o")
+                .with_timestamp(1234.5)
+                .render(),
+            " # {trace_id=\"KOO5S4vxi0o\"} 1.0 1234.5"
+        );
+        assert_eq!(Exemplar::new(f64::INFINITY).render(), " # {} +Inf");
+    }
+
+    #[test]
+    fn test_validate() {
+        let trace_id = "a".repeat(120);
+        assert_eq!(
+            Exemplar::new(1.0)
+                .with_label("trace_id", &trace_id)
+                .validate(),
+            Ok(())
+        );
+
+        let span_id = "b".repeat(10);
+        let too_long = Exemplar::new(1.0)
+            .with_label("trace_id", &trace_id)
+            .with_label("span_id", &span_id)
+            .validate()
+            .unwrap_err();
+        assert_eq!(too_long.length(), 145);
+    }
+}
@@ -0,0 +1,99 @@
+/// The text formats a scrape can be answered with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExpositionFormat {
+    /// The classic Prometheus text format, version 0.0.4.
+    #[default]
+    Text,
+    /// The OpenMetrics text format, version 1.0.0.
+    OpenMetrics,
+}
+
+impl ExpositionFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExpositionFormat::Text => "text/plain; version=0.0.4",
+            ExpositionFormat::OpenMetrics => {
+                "application/openmetrics-text; version=1.0.0; charset=utf-8"
+            }
+        }
+    }
+
+    /// Picks the format to answer with given the `Accept` header of the
+    /// scrape. OpenMetrics is chosen only if the client accepts it with a
+    /// quality greater than or equal to the classic text format.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use prometheus_exporter_base::ExpositionFormat;
+    ///
+    /// assert_eq!(
+    ///     ExpositionFormat::negotiate(Some(
+    ///         "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5"
+    ///     )),
+    ///     ExpositionFormat::OpenMetrics
+    /// );
+    /// assert_eq!(ExpositionFormat::negotiate(None), ExpositionFormat::Text);
+    /// ```
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        let mut openmetrics = None;
+        let mut text = None;
+
+        for media_range in accept.unwrap_or_default().split(',') {
+            let mut parameters = media_range.split(';').map(str::trim);
+            let media_type = parameters.next().unwrap_or_default();
+            let quality = parameters
+                .filter_map(|parameter| parameter.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            let slot = match media_type {
+                "application/openmetrics-text" => &mut openmetrics,
+                "text/plain" | "text/*" | "*/*" => &mut text,
+                _ => continue,
+            };
+            if !matches!(slot, Some(current) if *current >= quality) {
+                *slot = Some(quality);
+            }
+        }
+
+        match (openmetrics, text) {
+            (Some(openmetrics), Some(text)) if openmetrics > 0.0 && openmetrics >= text => {
+                ExpositionFormat::OpenMetrics
+            }
+            (Some(openmetrics), None) if openmetrics > 0.0 => ExpositionFormat::OpenMetrics,
+            _ => ExpositionFormat::Text,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(
+            ExpositionFormat::negotiate(Some("text/plain")),
+            ExpositionFormat::Text
+        );
+        assert_eq!(
+            ExpositionFormat::negotiate(Some("application/openmetrics-text")),
+            ExpositionFormat::OpenMetrics
+        );
+        assert_eq!(
+            ExpositionFormat::negotiate(Some(
+                "application/openmetrics-text;q=0.2, text/plain;version=0.0.4;q=0.5, */*;q=0.1"
+            )),
+            ExpositionFormat::Text
+        );
+        assert_eq!(
+            ExpositionFormat::negotiate(Some("application/openmetrics-text;q=0")),
+            ExpositionFormat::Text
+        );
+        assert_eq!(
+            ExpositionFormat::negotiate(Some("application/json")),
+            ExpositionFormat::Text
+        );
+    }
+}
@@ -43,20 +43,33 @@ extern crate serde_json;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 
+#[cfg(feature = "hyper_server")]
+use futures_util::future::try_join_all;
 #[cfg(feature = "hyper_server")]
 use http::StatusCode;
 #[cfg(feature = "hyper_server")]
 use hyper::{
-    body,
-    service::{make_service_fn, service_fn},
-    Body, Client, Request, Response, Server,
+    body::{self, HttpBody},
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn, Service},
+    Body, Client, Method, Request, Response,
 };
 #[cfg(feature = "hyper_server")]
 use serde::de::DeserializeOwned;
 #[cfg(feature = "hyper_server")]
 use std::future::Future;
 #[cfg(feature = "hyper_server")]
+use std::net::{IpAddr, SocketAddr};
+#[cfg(feature = "hyper_server")]
+use std::pin::Pin;
+#[cfg(feature = "hyper_server")]
 use std::sync::Arc;
+#[cfg(feature = "hyper_server")]
+use std::time::{Duration, Instant, SystemTime};
+#[cfg(all(feature = "hyper_server", unix))]
+use tokio::net::UnixStream;
+#[cfg(feature = "tls")]
+use tokio_rustls::server::TlsStream;
 
 mod prometheus_metric;
 mod render_to_prometheus;
@@ -66,16 +79,109 @@ pub use render_to_prometheus::RenderToPrometheus;
 mod metric_type;
 mod prometheus_instance;
 pub use metric_type::MetricType;
-pub use prometheus_instance::{MissingValue, PrometheusInstance};
+mod exposition_format;
+pub use exposition_format::ExpositionFormat;
+mod build_info;
+pub use build_info::BuildInfo;
+mod exemplar;
+pub use exemplar::{Exemplar, ExemplarTooLong, MAX_EXEMPLAR_LABELS_LENGTH};
+pub use prometheus_instance::{MissingValue, PrometheusInstance, StateSetError};
 pub mod prometheus_metric_builder;
 #[cfg(feature = "hyper_server")]
-use hyper::http::header::CONTENT_TYPE;
+use hyper::http::header::{
+    HeaderValue, ACCEPT, ACCEPT_ENCODING, ALLOW, AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE,
+    ETAG, IF_NONE_MATCH, PROXY_AUTHORIZATION, RETRY_AFTER, VARY,
+};
 #[cfg(feature = "hyper_server")]
 use std::error::Error;
 #[cfg(feature = "hyper_server")]
 mod server_options;
 #[cfg(feature = "hyper_server")]
 use server_options::*;
+#[cfg(feature = "hyper_server")]
+mod scrape_coordinator;
+#[cfg(feature = "hyper_server")]
+use scrape_coordinator::{CollectionError, QueueTimeout, ScrapeCoordinator, SharedOutcome};
+#[cfg(feature = "hyper_server")]
+mod scrape_cache;
+#[cfg(feature = "hyper_server")]
+use scrape_cache::{etag_matches, render_cache_age, CacheLookup, ScrapeCache};
+#[cfg(feature = "hyper_server")]
+mod poller;
+#[cfg(feature = "hyper_server")]
+use poller::SnapshotStore;
+#[cfg(feature = "hyper_server")]
+mod probe;
+#[cfg(feature = "hyper_server")]
+use probe::{render_probe, render_service_discovery};
+#[cfg(feature = "hyper_server")]
+pub use probe::{Probe, ProbeError};
+#[cfg(feature = "hyper_server")]
+mod scrape_context;
+#[cfg(feature = "hyper_server")]
+pub use scrape_context::ScrapeContext;
+#[cfg(feature = "hyper_server")]
+mod collector;
+#[cfg(feature = "hyper_server")]
+pub use async_trait::async_trait;
+#[cfg(feature = "hyper_server")]
+pub use collector::{Collector, CollectorRegistry, CollectorSelectionError};
+#[cfg(feature = "hyper_server")]
+mod collection;
+#[cfg(feature = "hyper_server")]
+pub use collection::{Collection, MetricsSender, ScrapeAborted};
+#[cfg(all(feature = "hyper_server", target_os = "linux"))]
+mod process_collector;
+#[cfg(all(feature = "hyper_server", target_os = "linux"))]
+pub use process_collector::ProcessCollector;
+#[cfg(feature = "hyper_server")]
+mod self_metrics;
+#[cfg(feature = "hyper_server")]
+use self_metrics::SelfMetrics;
+#[cfg(feature = "hyper_server")]
+mod compression;
+#[cfg(feature = "hyper_server")]
+use compression::Encoding;
+#[cfg(feature = "hyper_server")]
+mod catch_panic;
+#[cfg(feature = "hyper_server")]
+use catch_panic::{catch_panic, record_panic};
+#[cfg(feature = "hyper_server")]
+use futures_util::FutureExt;
+#[cfg(feature = "hyper_server")]
+use std::panic::AssertUnwindSafe;
+#[cfg(feature = "hyper_server")]
+mod connection;
+#[cfg(feature = "hyper_server")]
+use connection::{server_builder, IdleTimeout, InFlight};
+#[cfg(feature = "hyper_server")]
+mod listener;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "hyper_server")]
+use listener::Incoming;
+#[cfg(feature = "tls")]
+pub use tls::TlsOptions;
+#[cfg(feature = "hyper_server")]
+mod access_control;
+#[cfg(feature = "hyper_server")]
+pub use access_control::{AccessControl, InvalidIpNet, IpNet};
+#[cfg(feature = "hyper_server")]
+mod rate_limit;
+#[cfg(feature = "hyper_server")]
+use rate_limit::{retry_after_seconds, RateLimiter};
+#[cfg(feature = "hyper_server")]
+pub use rate_limit::{RateLimit, RateLimitKey};
+#[cfg(feature = "hyper_server")]
+mod access_log;
+#[cfg(feature = "hyper_server")]
+use access_log::AccessLogEntry;
+#[cfg(feature = "hyper_server")]
+pub use access_log::{AccessLogFormat, ACCESS_LOG_TARGET};
+#[cfg(feature = "hyper_server")]
+mod bad_request;
+#[cfg(feature = "hyper_server")]
+pub use bad_request::BadRequest;
 
 pub trait ToAssign {}
 #[derive(Debug, Clone, Copy)]
@@ -129,17 +235,433 @@ where
     Ok(t)
 }
 
+/// State shared by every connection of the server.
+#[cfg(feature = "hyper_server")]
+#[derive(Debug)]
+struct ServerState {
+    server_options: ServerOptions,
+    coordinator: ScrapeCoordinator,
+    cache: Option<ScrapeCache>,
+    snapshots: Option<SnapshotStore>,
+    self_metrics: SelfMetrics,
+    rate_limiter: Option<RateLimiter>,
+}
+
 #[cfg(feature = "hyper_server")]
-async fn serve_function<O, F, Fut>(
-    server_options: Arc<ServerOptions>,
+impl ServerState {
+    fn new(server_options: ServerOptions) -> Self {
+        Self {
+            coordinator: ScrapeCoordinator::new(&server_options),
+            cache: server_options
+                .cache_ttl
+                .map(|ttl| ScrapeCache::new(ttl, server_options.etag)),
+            snapshots: match server_options.collection_mode {
+                CollectionMode::OnScrape => None,
+                CollectionMode::Polling { .. } => Some(SnapshotStore::default()),
+            },
+            self_metrics: SelfMetrics::new(),
+            rate_limiter: server_options.rate_limit.clone().map(RateLimiter::new),
+            server_options,
+        }
+    }
+
+    /// The metrics appended to every `/metrics` scrape
+    /// after the output of the collector.
+    fn trailer(&self, cache_age: Option<Duration>, format: ExpositionFormat) -> String {
+        let mut s = cache_age.map(render_cache_age).unwrap_or_default();
+
+        if self.server_options.self_metrics {
+            s.push_str(&self.self_metrics.render(format));
+        }
+
+        if self.server_options.process_metrics {
+            #[cfg(target_os = "linux")]
+            match ProcessCollector::new().render(format) {
+                Ok(rendered) => s.push_str(&rendered),
+                Err(err) => warn!("cannot collect the process metrics == {:?}", err),
+            }
+        }
+
+        s
+    }
+}
+
+/// Copies everything but the body of the request so the collector
+/// can be called again without a client, for example to refresh the cache.
+#[cfg(feature = "hyper_server")]
+fn duplicate_request(req: &Request<Body>) -> Request<Body> {
+    let mut builder = Request::builder()
+        .method(req.method().clone())
+        .uri(req.uri().clone())
+        .version(req.version());
+    if let Some(headers) = builder.headers_mut() {
+        *headers = req.headers().clone();
+    }
+    if let Some(extensions) = builder.extensions_mut() {
+        if let Some(probe) = req.extensions().get::<Probe>() {
+            extensions.insert(probe.clone());
+        }
+        if let Some(context) = req.extensions().get::<ScrapeContext>() {
+            extensions.insert(context.clone());
+        }
+    }
+
+    builder.body(Body::empty()).unwrap()
+}
+
+#[cfg(feature = "hyper_server")]
+async fn collect<O, F, Fut, R>(
+    state: &ServerState,
+    key: &str,
     req: Request<Body>,
     f: F,
     options: Arc<O>,
-) -> Result<Response<Body>, hyper::Error>
+) -> Result<SharedOutcome, QueueTimeout>
 where
     F: FnOnce(Request<Body>, Arc<O>) -> Fut,
-    Fut: Future<Output = Result<String, Box<dyn Error + Send + Sync>>>,
-    O: std::fmt::Debug,
+    Fut: Future<Output = Result<R, Box<dyn Error + Send + Sync>>>,
+    R: Into<Collection>,
+{
+    let outcome = state
+        .coordinator
+        .collect(key, || async move {
+            let outcome = match catch_panic(|| f(req, options)).await.map(Into::into) {
+                Ok(mut collection) => {
+                    // a shared outcome can't be streamed to every scrape
+                    if state.cache.is_some() || state.server_options.coalesce_scrapes {
+                        collection.drain().await;
+                    }
+                    log_collection_errors(&collection);
+                    Ok(Arc::new(collection))
+                }
+                Err(err) => {
+                    let status = if err.is::<BadRequest>() {
+                        debug!("bad request == {:?}", err);
+                        StatusCode::BAD_REQUEST
+                    } else {
+                        warn!("internal server error == {:?}", err);
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    };
+
+                    Err(Arc::new(CollectionError {
+                        status,
+                        message: err.to_string(),
+                    }))
+                }
+            };
+
+            if let (Some(cache), Ok(collection)) = (&state.cache, &outcome) {
+                cache.store(key, collection.clone());
+            }
+
+            outcome
+        })
+        .await;
+
+    if let Some(cache) = &state.cache {
+        if !matches!(outcome, Ok(Ok(_))) {
+            cache.refresh_failed(key);
+        }
+    }
+
+    outcome
+}
+
+#[cfg(feature = "hyper_server")]
+fn log_collection_errors(collection: &Collection) {
+    for err in &collection.errors {
+        warn!("collection error == {:?}", err);
+    }
+}
+
+/// The `path` label of the self-metrics: unknown paths are grouped
+/// together to keep the cardinality bounded.
+#[cfg(feature = "hyper_server")]
+fn path_label<'a>(server_options: &'a ServerOptions, path: &str) -> &'a str {
+    let probe = server_options.probe.as_ref();
+    let service_discovery_path = probe.and_then(|probe| probe.service_discovery_path.as_deref());
+
+    if path == "/metrics" {
+        "/metrics"
+    } else if path == "/probe" && probe.is_some() {
+        "/probe"
+    } else if service_discovery_path == Some(path) {
+        service_discovery_path.unwrap()
+    } else {
+        "other"
+    }
+}
+
+#[cfg(feature = "hyper_server")]
+async fn serve_function<O, F, Fut, R>(
+    state: Arc<ServerState>,
+    peer: Option<SocketAddr>,
+    req: Request<Body>,
+    f: F,
+    options: Arc<O>,
+) -> Result<Response<Body>, hyper::Error>
+where
+    F: FnOnce(Request<Body>, Arc<O>) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<R, Box<dyn Error + Send + Sync>>> + Send + 'static,
+    R: Into<Collection> + 'static,
+    O: std::fmt::Debug + Sync + Send + 'static,
+{
+    let started = Instant::now();
+    let time = SystemTime::now();
+    let path = path_label(&state.server_options, req.uri().path()).to_owned();
+    let requester = requester(&state.server_options, peer, &req);
+    #[cfg(feature = "tracing")]
+    {
+        let span = tracing::Span::current();
+        if let Some(client) = requester.client {
+            span.record("client", tracing::field::display(client));
+        }
+        if let Some(user) = &requester.user {
+            span.record("user", user.as_str());
+        }
+    }
+    let (client, user) = (requester.client, requester.user.clone());
+    let (method, uri, version) = (req.method().clone(), req.uri().clone(), req.version());
+    let encoding = Encoding::negotiate(
+        req.headers()
+            .get(ACCEPT_ENCODING)
+            .and_then(|accept_encoding| accept_encoding.to_str().ok()),
+    );
+
+    // the collector panics are caught closer to it, this is the last resort
+    let handled = AssertUnwindSafe(handle_request(
+        state.clone(),
+        peer,
+        requester,
+        req,
+        f,
+        options,
+    ))
+    .catch_unwind()
+    .await;
+    let mut response = match handled {
+        Ok(response) => response?,
+        Err(payload) => {
+            record_panic(payload.as_ref());
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from("internal server error"))
+                .unwrap()
+        }
+    };
+    if state.server_options.compression {
+        response = compress(
+            response,
+            encoding,
+            state.server_options.compression_min_size,
+        )
+        .await?;
+    }
+
+    if state.server_options.self_metrics {
+        state.self_metrics.record(
+            &path,
+            response.status(),
+            started.elapsed(),
+            response.body().size_hint().exact(),
+        );
+    }
+
+    #[cfg(feature = "tracing")]
+    {
+        let span = tracing::Span::current();
+        span.record("status", response.status().as_u16());
+        if let Some(bytes) = response.body().size_hint().exact() {
+            span.record("bytes", bytes);
+        }
+    }
+
+    if let Some(format) = state.server_options.access_log {
+        let entry = AccessLogEntry {
+            time,
+            client,
+            peer,
+            user: user.as_deref(),
+            method: &method,
+            path: uri.path(),
+            query: uri.query(),
+            version,
+            status: response.status(),
+            // hyper never sends the body of the responses to HEAD requests
+            bytes: if method == Method::HEAD {
+                Some(0)
+            } else {
+                response.body().size_hint().exact()
+            },
+            latency: started.elapsed(),
+        };
+        info!(target: ACCESS_LOG_TARGET, "{}", entry.render(format));
+    }
+
+    Ok(response)
+}
+
+/// Who sent a request, as far as the access control and the
+/// authorization can tell.
+#[cfg(feature = "hyper_server")]
+struct Requester {
+    /// The client address, `None` for connections without one like the
+    /// Unix domain socket ones.
+    client: Option<IpAddr>,
+    is_authorized: bool,
+    /// The authenticated user.
+    user: Option<String>,
+}
+
+/// The `X-Forwarded-For` hops of the request. Proxies may append a
+/// header line instead of extending the existing one, so every line
+/// counts. A line that is not UTF-8 becomes an empty hop, which stops
+/// the walk of [`AccessControl`] there.
+///
+/// [`AccessControl`]: struct.AccessControl.html
+#[cfg(feature = "hyper_server")]
+fn forwarded_for(req: &Request<Body>) -> Option<String> {
+    let lines = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .map(|line| line.to_str().unwrap_or_default())
+        .collect::<Vec<_>>();
+
+    if lines.is_empty() {
+        None
+    } else {
+        Some(lines.join(","))
+    }
+}
+
+#[cfg(feature = "hyper_server")]
+fn requester(
+    server_options: &ServerOptions,
+    peer: Option<SocketAddr>,
+    req: &Request<Body>,
+) -> Requester {
+    #[cfg(feature = "tracing")]
+    let span = tracing::info_span!("authorize", authorized = tracing::field::Empty).entered();
+
+    let client = peer.map(|peer| match &server_options.access_control {
+        Some(access_control) => {
+            access_control.client_addr(peer.ip(), forwarded_for(req).as_deref())
+        }
+        None => peer.ip(),
+    });
+
+    // check auth if necessary, keeping track of the authenticated user
+    let (is_authorized, user) = match &server_options.authorization {
+        Authorization::Basic(password) => {
+            let user = req
+                .headers()
+                .iter()
+                .find(|(header_name, _)| header_name.as_str() == "authorization")
+                .map_or_else(
+                    || Ok::<_, Box<dyn Error + Send + Sync>>(None),
+                    |(_header_name, header_value)| {
+                        let header_value_as_str = header_value.to_str()?;
+                        let tokens: Vec<_> = header_value_as_str.split(' ').collect();
+                        if tokens.len() != 2 {
+                            return Ok(None);
+                        }
+                        if tokens[0] != "Basic" {
+                            return Ok(None);
+                        }
+                        let base64_decoded = base64::decode(tokens[1])?;
+                        let password_from_header = std::str::from_utf8(&base64_decoded)?;
+                        // the user name is not checked so it must be empty
+                        Ok(password_from_header
+                            .split_once(':')
+                            .filter(|(user, pass)| user.is_empty() && pass == password)
+                            .map(|(user, _)| user.to_owned()))
+                    },
+                )
+                .unwrap_or(None);
+
+            (user.is_some(), user)
+        }
+        Authorization::None => (true, None),
+    };
+
+    #[cfg(feature = "tracing")]
+    span.record("authorized", is_authorized);
+
+    Requester {
+        client,
+        is_authorized,
+        user,
+    }
+}
+
+/// Compresses successful responses of at least `min_size` bytes.
+#[cfg(feature = "hyper_server")]
+async fn compress(
+    mut response: Response<Body>,
+    encoding: Encoding,
+    min_size: usize,
+) -> Result<Response<Body>, hyper::Error> {
+    if response.status() != StatusCode::OK || response.headers().contains_key(CONTENT_ENCODING) {
+        return Ok(response);
+    }
+    response
+        .headers_mut()
+        .insert(VARY, HeaderValue::from_static("accept-encoding"));
+
+    let content_encoding = match encoding.content_encoding() {
+        Some(content_encoding) => content_encoding,
+        None => return Ok(response),
+    };
+    if !matches!(response.body().size_hint().exact(), Some(size) if size >= min_size as u64) {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = body::to_bytes(body).await?;
+
+    // compressing megabytes of text would stall the other connections
+    let uncompressed = body.clone();
+    Ok(
+        match tokio::task::spawn_blocking(move || encoding.encode(&uncompressed)).await {
+            Ok(Ok(encoded)) => {
+                trace!(
+                    "compressed {} bytes into {} with {}",
+                    body.len(),
+                    encoded.len(),
+                    content_encoding
+                );
+                parts
+                    .headers
+                    .insert(CONTENT_ENCODING, HeaderValue::from_static(content_encoding));
+                Response::from_parts(parts, Body::from(encoded))
+            }
+            Ok(Err(err)) => {
+                warn!("cannot compress the response == {:?}", err);
+                Response::from_parts(parts, Body::from(body))
+            }
+            Err(err) => {
+                warn!("cannot compress the response == {:?}", err);
+                Response::from_parts(parts, Body::from(body))
+            }
+        },
+    )
+}
+
+#[cfg(feature = "hyper_server")]
+async fn handle_request<O, F, Fut, R>(
+    state: Arc<ServerState>,
+    peer: Option<SocketAddr>,
+    requester: Requester,
+    req: Request<Body>,
+    f: F,
+    options: Arc<O>,
+) -> Result<Response<Body>, hyper::Error>
+where
+    F: FnOnce(Request<Body>, Arc<O>) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<R, Box<dyn Error + Send + Sync>>> + Send + 'static,
+    R: Into<Collection> + 'static,
+    O: std::fmt::Debug + Sync + Send + 'static,
 {
     trace!(
         "serve_function:: req.uri() == {}, req.method() == {}",
@@ -152,122 +674,1136 @@ where
         req.headers()
             .iter()
             .map(|(header_name, header_value)| {
-                format!("{} => {}", header_name, header_value.to_str().unwrap())
+                // the credentials never end up in the logs
+                if header_name == AUTHORIZATION || header_name == PROXY_AUTHORIZATION {
+                    format!("{} => <redacted>", header_name)
+                } else {
+                    format!(
+                        "{} => {}",
+                        header_name,
+                        header_value.to_str().unwrap_or("<not UTF-8>")
+                    )
+                }
             })
             .collect::<Vec<_>>()
             .join("\n")
     );
 
-    // check auth if necessary
-    let is_authorized = match &server_options.authorization {
-        Authorization::Basic(password) => req
-            .headers()
-            .iter()
-            .find(|(header_name, _)| header_name.as_str() == "authorization")
-            .map_or_else(
-                || Ok::<_, Box<dyn Error + Send + Sync>>(false),
-                |(_header_name, header_value)| {
-                    let header_value_as_str = header_value.to_str()?;
-                    let tokens: Vec<_> = header_value_as_str.split(' ').collect();
-                    if tokens.len() != 2 {
-                        return Ok(false);
-                    }
-                    if tokens[0] != "Basic" {
-                        return Ok(false);
-                    }
-                    trace!("Authorization tokens == {:?}", tokens);
-                    let base64_decoded = base64::decode(tokens[1])?;
-                    let password_from_header = std::str::from_utf8(&base64_decoded)?;
-                    Ok(format!(":{}", password) == password_from_header)
-                },
-            )
-            .unwrap_or(false),
-        Authorization::None => true,
+    let Requester {
+        client,
+        is_authorized,
+        user: identity,
+    } = requester;
+
+    // the client address is checked before anything else
+    let access_control = state.server_options.access_control.as_ref();
+    if let (Some(access_control), Some(client), Some(peer)) = (access_control, client, peer) {
+        if !access_control.is_allowed(client) {
+            debug!("refusing the request of {} (peer {})", client, peer);
+            return Ok(Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(hyper::Body::empty())
+                .unwrap());
+        }
+    }
+
+    let format = if state.server_options.openmetrics {
+        ExpositionFormat::negotiate(
+            req.headers()
+                .get(ACCEPT)
+                .and_then(|accept| accept.to_str().ok()),
+        )
+    } else {
+        ExpositionFormat::Text
     };
+    let is_probe = req.uri().path() == "/probe" && state.server_options.probe.is_some();
+    let is_service_discovery = state
+        .server_options
+        .probe
+        .as_ref()
+        .and_then(|probe| probe.service_discovery_path.as_deref())
+        == Some(req.uri().path());
 
-    if !is_authorized {
+    // checked before the authorization so guessing credentials is throttled too
+    let rate_limited = state.rate_limiter.as_ref().and_then(|rate_limiter| {
+        let key = match (rate_limiter.key(), &identity) {
+            // the Basic authorization users are always empty and
+            // the users of unauthorized requests are not verified
+            (RateLimitKey::User, Some(user)) if is_authorized && !user.is_empty() => {
+                Some(format!("user {}", user))
+            }
+            _ => client.map(|client| client.to_string()),
+        };
+        // connections without an address, like Unix sockets, are not limited
+        key.and_then(|key| rate_limiter.acquire(&key, Instant::now()).err())
+    });
+
+    if let Some(retry_after) = rate_limited {
+        state.self_metrics.record_rate_limited();
+        Ok(Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(RETRY_AFTER, retry_after_seconds(retry_after))
+            .body(hyper::Body::empty())
+            .unwrap())
+    } else if !is_authorized {
         Ok(Response::builder()
             .status(StatusCode::UNAUTHORIZED)
             .body(hyper::Body::empty())
             .unwrap())
-    } else if req.uri().path() != "/metrics" {
+    } else if matches!(
+        state.server_options.max_request_body_size,
+        Some(max_request_body_size) if req.body().size_hint().lower() > max_request_body_size
+    ) {
+        Ok(Response::builder()
+            .status(StatusCode::PAYLOAD_TOO_LARGE)
+            .body(hyper::Body::empty())
+            .unwrap())
+    } else if req.uri().path() != "/metrics" && !is_probe && !is_service_discovery {
         Ok(Response::builder()
             .status(StatusCode::NOT_FOUND)
             .body(hyper::Body::empty())
             .unwrap())
-    } else if req.method() != "GET" {
+    } else if req.method() != Method::GET && req.method() != Method::HEAD {
         Ok(Response::builder()
             .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(ALLOW, "GET, HEAD")
             .body(hyper::Body::empty())
             .unwrap())
-    } else {
-        // everything is ok, let's call the supplied future
-        trace!("serve_function:: options == {:?}", options);
+    } else if req.method() == Method::HEAD && !is_service_discovery && state.snapshots.is_none() {
+        // the body would be thrown away, don't hit the backend for it
+        let probe = if is_probe {
+            let probe_options = state.server_options.probe.as_ref().unwrap();
+            match Probe::from_query(req.uri().query(), probe_options) {
+                Ok(probe) => Some(probe),
+                Err(_) => {
+                    return Ok(Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(hyper::Body::empty())
+                        .unwrap())
+                }
+            }
+        } else {
+            None
+        };
 
-        Ok(match f(req, options).await {
-            Ok(response) => Response::builder()
+        Ok(head_response(
+            &state,
+            &scrape_key(&req, format, probe.as_ref()),
+            format,
+            req.headers()
+                .get(IF_NONE_MATCH)
+                .and_then(|if_none_match| if_none_match.to_str().ok()),
+        ))
+    } else if is_service_discovery {
+        let probe_options = state.server_options.probe.as_ref().unwrap();
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(render_service_discovery(probe_options)))
+            .unwrap())
+    } else if is_probe {
+        let probe_options = state.server_options.probe.as_ref().unwrap();
+
+        Ok(match Probe::from_query(req.uri().query(), probe_options) {
+            Ok(probe) => {
+                trace!("serve_function:: probe == {:?}", probe);
+
+                let key = scrape_key(&req, format, Some(&probe));
+                let mut req = req;
+                req.extensions_mut().insert(probe);
+                let context = ScrapeContext::from_request(
+                    &req,
+                    &state.server_options.context_headers,
+                    peer,
+                    identity,
+                    format,
+                );
+                req.extensions_mut().insert(context);
+
+                // a failed probe is still a successful scrape
+                let outcome = state
+                    .coordinator
+                    .collect(&key, || async move {
+                        let started = Instant::now();
+                        let mut collection =
+                            match catch_panic(|| f(req, options)).await.map(Into::into) {
+                                Ok(collection) => collection,
+                                Err(err) => {
+                                    let mut collection = Collection::new();
+                                    collection.push_error(err);
+                                    collection
+                                }
+                            };
+                        collection.drain().await;
+                        log_collection_errors(&collection);
+
+                        let probe_metrics =
+                            render_probe(collection.errors.is_empty(), started.elapsed());
+                        collection.push_metrics(&probe_metrics);
+                        Ok(Arc::new(collection))
+                    })
+                    .await;
+
+                outcome_response(outcome, String::new(), format, None)
+            }
+            Err(err) => Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(err.to_string()))
+                .unwrap(),
+        })
+    } else if let Some(snapshots) = &state.snapshots {
+        // the collector runs in the background, serve its latest output
+        Ok(match snapshots.latest() {
+            Some(snapshot) => Response::builder()
                 .status(StatusCode::OK)
-                .header(CONTENT_TYPE, "text/plain; version=0.0.4")
-                .body(Body::from(response))
+                .header(CONTENT_TYPE, ExpositionFormat::Text.content_type())
+                .body(Body::from(
+                    snapshot.render() + &state.trailer(None, ExpositionFormat::Text),
+                ))
                 .unwrap(),
-            Err(err) => {
-                warn!("internal server error == {:?}", err);
+            None => {
+                warn!("no background collection succeeded yet, rejecting the scrape");
 
                 Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(Body::from(err.to_string()))
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(hyper::Body::empty())
                     .unwrap()
             }
         })
+    } else {
+        // everything is ok, let's call the supplied future
+        trace!("serve_function:: options == {:?}", options);
+
+        let key = scrape_key(&req, format, None);
+        let context = ScrapeContext::from_request(
+            &req,
+            &state.server_options.context_headers,
+            peer,
+            identity,
+            format,
+        );
+        let mut req = req;
+        req.extensions_mut().insert(context);
+
+        let if_none_match = req
+            .headers()
+            .get(IF_NONE_MATCH)
+            .and_then(|if_none_match| if_none_match.to_str().ok())
+            .map(str::to_owned);
+
+        let (outcome, cache_age, etag) = match state.cache.as_ref().map(|cache| cache.lookup(&key))
+        {
+            Some(CacheLookup::Hit {
+                collection,
+                age,
+                refresh,
+                etag,
+            }) => {
+                if refresh {
+                    debug!("cached collection of {} is stale, refreshing it", key);
+
+                    let state = state.clone();
+                    let req = duplicate_request(&req);
+                    tokio::spawn(async move {
+                        let _ = collect(&state, &key, req, f, options).await;
+                    });
+                }
+
+                (Ok(Ok(collection)), Some(age), etag)
+            }
+            Some(CacheLookup::Miss) => {
+                let outcome = collect(&state, &key, req, f, options).await;
+                let etag = state.cache.as_ref().and_then(|cache| cache.etag(&key));
+
+                (outcome, Some(Duration::from_secs(0)), etag)
+            }
+            None => (collect(&state, &key, req, f, options).await, None, None),
+        };
+
+        if let (Some(etag), Some(if_none_match)) = (&etag, &if_none_match) {
+            if etag_matches(if_none_match, etag) {
+                trace!("serve_function:: {} not modified", etag);
+
+                return Ok(Response::builder()
+                    .status(StatusCode::NOT_MODIFIED)
+                    .header(ETAG, etag.as_str())
+                    .body(hyper::Body::empty())
+                    .unwrap());
+            }
+        }
+
+        // the trailer changes on every scrape, the tagged responses go without it
+        let trailer = match etag {
+            Some(_) => String::new(),
+            None => state.trailer(cache_age, format),
+        };
+        let mut response = outcome_response(
+            outcome,
+            trailer,
+            format,
+            state.server_options.scrape_error_threshold,
+        );
+        if let Some(etag) = etag {
+            if response.status() == StatusCode::OK {
+                if let Ok(etag) = HeaderValue::from_str(&etag) {
+                    response.headers_mut().insert(ETAG, etag);
+                }
+            }
+        }
+
+        Ok(response)
+    }
+}
+
+/// Answers a `HEAD` scrape without calling the collector, with the
+/// `ETag` of the cached collection if there is one.
+#[cfg(feature = "hyper_server")]
+fn head_response(
+    state: &ServerState,
+    key: &str,
+    format: ExpositionFormat,
+    if_none_match: Option<&str>,
+) -> Response<Body> {
+    let etag = state.cache.as_ref().and_then(|cache| cache.etag(key));
+    let status = match (&etag, if_none_match) {
+        (Some(etag), Some(if_none_match)) if etag_matches(if_none_match, etag) => {
+            StatusCode::NOT_MODIFIED
+        }
+        _ => StatusCode::OK,
+    };
+
+    let mut response = Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, format.content_type());
+    if let Some(etag) = etag {
+        response = response.header(ETAG, etag);
+    }
+
+    response.body(hyper::Body::empty()).unwrap()
+}
+
+/// Scrapes share the same collection and cache entry when they ask for
+/// the same output: same path and format, same `collect[]` selection
+/// and, for `/probe`, same target and module. The other query parameters
+/// are ignored so a client can't bypass the cache by adding some.
+#[cfg(feature = "hyper_server")]
+fn scrape_key(req: &Request<Body>, format: ExpositionFormat, probe: Option<&Probe>) -> String {
+    let mut collect = form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+        .filter(|(name, _)| name == "collect[]")
+        .map(|(_, value)| value.into_owned())
+        .collect::<Vec<_>>();
+    collect.sort();
+    collect.dedup();
+
+    let mut key = format!("{:?} {} {:?}", format, req.uri().path(), collect);
+    if let Some(probe) = probe {
+        key.push_str(&format!(" {:?} {:?}", probe.target, probe.module));
+    }
+
+    key
+}
+
+#[cfg(feature = "hyper_server")]
+fn outcome_response(
+    outcome: Result<SharedOutcome, QueueTimeout>,
+    trailer: String,
+    format: ExpositionFormat,
+    scrape_error_threshold: Option<usize>,
+) -> Response<Body> {
+    match outcome {
+        Ok(Ok(collection)) if matches!(scrape_error_threshold, Some(threshold) if collection.errors.len() >= threshold) =>
+        {
+            warn!(
+                "{} collection errors, failing the scrape",
+                collection.errors.len()
+            );
+
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(
+                    collection
+                        .errors
+                        .iter()
+                        .map(|err| err.to_string())
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                ))
+                .unwrap()
+        }
+        Ok(Ok(collection)) => {
+            let mut trailer = trailer;
+            if format == ExpositionFormat::OpenMetrics {
+                trailer.push_str("# EOF\n");
+            }
+
+            let body = match Arc::try_unwrap(collection) {
+                Ok(collection) => collection.into_body(trailer),
+                Err(shared) => Body::from(shared.render() + &trailer),
+            };
+
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, format.content_type())
+                .body(body)
+                .unwrap()
+        }
+        Ok(Err(err)) => Response::builder()
+            .status(err.status)
+            .body(Body::from(err.message.clone()))
+            .unwrap(),
+        Err(QueueTimeout {}) => {
+            warn!("no collection slot became free in time, rejecting the scrape");
+
+            Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(hyper::Body::empty())
+                .unwrap()
+        }
     }
 }
 
+/// The service answering the requests of a single connection.
+#[cfg(feature = "hyper_server")]
+fn connection_service<O, F, Fut, R>(
+    state: Arc<ServerState>,
+    peer: Option<SocketAddr>,
+    in_flight: InFlight,
+    f: F,
+    options: Arc<O>,
+) -> impl Service<
+    Request<Body>,
+    Response = Response<Body>,
+    Error = hyper::Error,
+    Future = impl Future<Output = Result<Response<Body>, hyper::Error>> + Send,
+> + Send
+where
+    F: FnOnce(Request<Body>, Arc<O>) -> Fut + Send + Clone + Sync + 'static,
+    Fut: Future<Output = Result<R, Box<dyn Error + Send + Sync>>> + Send + 'static,
+    R: Into<Collection> + 'static,
+    O: std::fmt::Debug + Sync + Send + 'static,
+{
+    service_fn(move |req: Request<Body>| {
+        let guard = in_flight.enter();
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "scrape",
+            method = %req.method(),
+            path = req.uri().path(),
+            peer = tracing::field::debug(peer),
+            client = tracing::field::Empty,
+            user = tracing::field::Empty,
+            status = tracing::field::Empty,
+            bytes = tracing::field::Empty,
+        );
+        let response = serve_function(state.clone(), peer, req, f.clone(), options.clone());
+        #[cfg(feature = "tracing")]
+        let response = tracing::Instrument::instrument(response, span);
+        async move {
+            let response = response.await;
+            drop(guard);
+            response
+        }
+    })
+}
+
 #[cfg(feature = "hyper_server")]
-async fn run_server<O, F, Fut>(
+async fn run_server<O, F, Fut, R>(
     server_options: ServerOptions,
     options: Arc<O>,
     f: F,
-) -> Result<(), hyper::Error>
+) -> Result<(), Box<dyn Error + Send + Sync>>
 where
     F: FnOnce(Request<Body>, Arc<O>) -> Fut + Send + Clone + Sync + 'static,
-    Fut: Future<Output = Result<String, Box<dyn Error + Send + Sync>>> + Send + 'static,
+    Fut: Future<Output = Result<R, Box<dyn Error + Send + Sync>>> + Send + 'static,
+    R: Into<Collection> + 'static,
     O: std::fmt::Debug + Sync + Send + 'static,
 {
-    info!("Listening on http://{}/metrics", server_options.addr);
+    if server_options.process_metrics && !cfg!(target_os = "linux") {
+        warn!("process metrics are only available on Linux, ignoring them");
+    }
+
+    #[cfg(feature = "tls")]
+    let tls_acceptor = match &server_options.tls {
+        Some(tls_options) => Some(tls::acceptor(tls_options, server_options.http_versions)?),
+        None => None,
+    };
+
+    let mut incomings = Vec::new();
+    #[cfg(unix)]
+    if server_options.socket_activation {
+        for socket in listener::activated()? {
+            info!(
+                "Listening on {}/metrics, systemd socket {}",
+                socket.addr, socket.name
+            );
+            incomings.push(socket.incoming);
+        }
+    }
+    #[cfg(not(unix))]
+    if server_options.socket_activation {
+        warn!("socket activation is only available on Unix, ignoring it");
+    }
+
+    // bind everything first so a failure leaves nothing half started
+    if incomings.is_empty() {
+        if server_options.listeners.is_empty() {
+            return Err("no address to listen on".into());
+        }
+        for listen_addr in &server_options.listeners {
+            let incoming = listener::bind(listen_addr)
+                .map_err(|err| format!("cannot listen on {}: {}", listen_addr, err))?;
+            info!("Listening on {}/metrics", listen_addr);
+            incomings.push(incoming);
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    if let Some(tls_acceptor) = &tls_acceptor {
+        info!("Serving HTTPS on the TCP listeners");
+        incomings = incomings
+            .into_iter()
+            .map(|incoming| incoming.with_tls(tls_acceptor))
+            .collect();
+    }
 
     let f = f.clone();
     let options = options.clone();
-    let addr = server_options.addr;
-    let server_options = Arc::new(server_options);
+    let state = Arc::new(ServerState::new(server_options));
 
-    let make_service = make_service_fn(move |_| {
-        let f = f.clone();
-        let options = options.clone();
-        let server_options = server_options.clone();
+    let poller = match state.server_options.collection_mode {
+        CollectionMode::Polling {
+            interval,
+            max_backoff,
+        } => {
+            info!("Polling the collector every {:?}", interval);
 
-        async move {
-            Ok::<_, hyper::Error>(service_fn(move |req| {
-                serve_function(server_options.clone(), req, f.clone(), options.clone())
+            let state = state.clone();
+            let f = f.clone();
+            let options = options.clone();
+            Some(tokio::spawn(async move {
+                let snapshots = state.snapshots.as_ref().unwrap();
+                let req = || {
+                    let mut req = Request::get("/metrics").body(Body::empty()).unwrap();
+                    req.extensions_mut().insert(ScrapeContext {
+                        path: "/metrics".to_owned(),
+                        ..ScrapeContext::default()
+                    });
+                    req
+                };
+
+                poller::poll(snapshots, interval, max_backoff, || {
+                    let f = f.clone();
+                    let req = req();
+                    let options = options.clone();
+                    catch_panic(move || f(req, options))
+                })
+                .await
             }))
         }
-    });
+        CollectionMode::OnScrape => None,
+    };
 
-    let serve_future = Server::bind(&addr).serve(make_service);
+    let servers = incomings.into_iter().map(|incoming| {
+        let state = state.clone();
+        let f = f.clone();
+        let options = options.clone();
 
-    serve_future.await
+        let server: Pin<Box<dyn Future<Output = Result<(), hyper::Error>> + Send>> = match incoming
+        {
+            Incoming::Tcp(incoming) => Box::pin(
+                server_builder(incoming, &state.server_options).serve(make_service_fn(
+                    move |conn: &IdleTimeout<AddrStream>| {
+                        let service = connection_service(
+                            state.clone(),
+                            Some(conn.get_ref().remote_addr()),
+                            conn.in_flight(),
+                            f.clone(),
+                            options.clone(),
+                        );
+                        async move { Ok::<_, hyper::Error>(service) }
+                    },
+                )),
+            ),
+            #[cfg(feature = "tls")]
+            Incoming::Tls(incoming) => Box::pin(
+                server_builder(incoming, &state.server_options).serve(make_service_fn(
+                    move |conn: &IdleTimeout<TlsStream<AddrStream>>| {
+                        let service = connection_service(
+                            state.clone(),
+                            Some(conn.get_ref().get_ref().0.remote_addr()),
+                            conn.in_flight(),
+                            f.clone(),
+                            options.clone(),
+                        );
+                        async move { Ok::<_, hyper::Error>(service) }
+                    },
+                )),
+            ),
+            #[cfg(unix)]
+            Incoming::Unix(incoming) => Box::pin(
+                server_builder(incoming, &state.server_options).serve(make_service_fn(
+                    move |conn: &IdleTimeout<UnixStream>| {
+                        let service = connection_service(
+                            state.clone(),
+                            None,
+                            conn.in_flight(),
+                            f.clone(),
+                            options.clone(),
+                        );
+                        async move { Ok::<_, hyper::Error>(service) }
+                    },
+                )),
+            ),
+        };
+        server
+    });
+
+    // the first listener failing stops every other one
+    let result = try_join_all(servers).await;
+    if let Some(poller) = poller {
+        poller.abort();
+    }
+    result?;
+    Ok(())
 }
 
+/// Serves the output of `f` until the server fails. The error is
+/// returned, for example when a listener cannot be bound.
 #[cfg(feature = "hyper_server")]
-pub async fn render_prometheus<O, F, Fut>(server_options: ServerOptions, options: O, f: F)
+pub async fn render_prometheus<O, F, Fut, R>(
+    server_options: ServerOptions,
+    options: O,
+    f: F,
+) -> Result<(), Box<dyn Error + Send + Sync>>
 where
     F: FnOnce(Request<Body>, Arc<O>) -> Fut + Send + Clone + Sync + 'static,
-    Fut: Future<Output = Result<String, Box<dyn Error + Send + Sync>>> + Send + 'static,
+    Fut: Future<Output = Result<R, Box<dyn Error + Send + Sync>>> + Send + 'static,
+    R: Into<Collection> + 'static,
     O: std::fmt::Debug + Sync + Send + 'static,
 {
-    let o = Arc::new(options);
+    run_server(server_options, Arc::new(options), f).await
+}
 
-    let _ = run_server(server_options, o, f).await.map_err(|err| {
-        error!("{:?}", err);
-        eprintln!("Server failure: {:?}", err)
-    });
+/// Works like [`render_prometheus`] but the closure receives a [`ScrapeContext`]
+/// instead of the raw `hyper` request.
+///
+/// [`render_prometheus`]: fn.render_prometheus.html
+/// [`ScrapeContext`]: struct.ScrapeContext.html
+///
+/// # Examples
+///
+/// ```no_run
+/// use prometheus_exporter_base::prelude::*;
+///
+/// # async fn run(server_options: ServerOptions) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// render_prometheus_with_context(server_options, (), |context, _options| async move {
+///     let folder = context.query_param("folder").unwrap_or("/var/log").to_owned();
+///
+///     Ok(PrometheusMetric::build()
+///         .with_name("folder_size")
+///         .with_metric_type(MetricType::Gauge)
+///         .with_help("Size of the folder")
+///         .build()
+///         .render_and_append_instance(
+///             &PrometheusInstance::new()
+///                 .with_label("folder", folder.as_str())
+///                 .with_value(100),
+///         )
+///         .render())
+/// })
+/// .await
+/// # }
+/// ```
+#[cfg(feature = "hyper_server")]
+pub async fn render_prometheus_with_context<O, F, Fut, R>(
+    server_options: ServerOptions,
+    options: O,
+    f: F,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    F: FnOnce(ScrapeContext, Arc<O>) -> Fut + Send + Clone + Sync + 'static,
+    Fut: Future<Output = Result<R, Box<dyn Error + Send + Sync>>> + Send + 'static,
+    R: Into<Collection> + 'static,
+    O: std::fmt::Debug + Sync + Send + 'static,
+{
+    render_prometheus(
+        server_options,
+        options,
+        move |mut request: Request<Body>, options| {
+            // serve_function always attaches the context to the request
+            let context = request
+                .extensions_mut()
+                .remove::<ScrapeContext>()
+                .unwrap_or_default();
+            f(context, options)
+        },
+    )
+    .await
+}
+
+/// How many rendered chunks [`render_prometheus_stream`] keeps in memory
+/// while waiting for a slow client.
+///
+/// [`render_prometheus_stream`]: fn.render_prometheus_stream.html
+#[cfg(feature = "hyper_server")]
+const STREAM_BUFFER: usize = 16;
+
+/// Works like [`render_prometheus_with_context`] but the closure sends the
+/// metric families through a [`MetricsSender`] as soon as they are rendered.
+/// They are forwarded to the client as a chunked response so very large
+/// expositions are never held in memory as a whole.
+///
+/// Streams can't be shared so scrape coalescing and the scrape cache are
+/// turned off; check [`Collection::streaming`] for the other limitations.
+/// An error returned by the closure is counted by `exporter_scrape_errors`.
+///
+/// [`render_prometheus_with_context`]: fn.render_prometheus_with_context.html
+/// [`MetricsSender`]: struct.MetricsSender.html
+/// [`Collection::streaming`]: struct.Collection.html#method.streaming
+///
+/// # Examples
+///
+/// ```no_run
+/// use prometheus_exporter_base::prelude::*;
+///
+/// # async fn run(server_options: ServerOptions) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// render_prometheus_stream(server_options, (), |_context, _options, sender| async move {
+///     for shard in 0..1000 {
+///         let shard = shard.to_string();
+///         sender
+///             .send(
+///                 PrometheusMetric::build()
+///                     .with_name("shard_size")
+///                     .with_metric_type(MetricType::Gauge)
+///                     .with_help("Size of the shard")
+///                     .build()
+///                     .render_and_append_instance(
+///                         &PrometheusInstance::new()
+///                             .with_label("shard", shard.as_str())
+///                             .with_value(100),
+///                     )
+///                     .render(),
+///             )
+///             .await?;
+///     }
+///     Ok(())
+/// })
+/// .await
+/// # }
+/// ```
+#[cfg(feature = "hyper_server")]
+pub async fn render_prometheus_stream<O, F, Fut>(
+    server_options: ServerOptions,
+    options: O,
+    f: F,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    F: FnOnce(ScrapeContext, Arc<O>, MetricsSender) -> Fut + Send + Clone + Sync + 'static,
+    Fut: Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send + 'static,
+    O: std::fmt::Debug + Sync + Send + 'static,
+{
+    let server_options = ServerOptions {
+        coalesce_scrapes: false,
+        cache_ttl: None,
+        ..server_options
+    };
+
+    render_prometheus_with_context(server_options, options, |context, options| async move {
+        let (collection, sender) = Collection::streaming(STREAM_BUFFER);
+        tokio::spawn(async move {
+            let collector_sender = sender.clone();
+            if let Err(err) = catch_panic(|| f(context, options, collector_sender)).await {
+                let _ = sender.send_error(err).await;
+            }
+        });
+
+        Ok::<_, Box<dyn Error + Send + Sync>>(collection)
+    })
+    .await
+}
+
+/// Serves the metrics of every collector registered in `registry`.
+/// Collectors run concurrently and a failing one does not fail the
+/// scrape, check [`CollectorRegistry`] for details. Scrapes selecting
+/// unknown or disabled collectors with `collect[]` are answered with
+/// `400 Bad Request`.
+///
+/// [`CollectorRegistry`]: struct.CollectorRegistry.html
+#[cfg(feature = "hyper_server")]
+pub async fn render_collectors(
+    server_options: ServerOptions,
+    registry: CollectorRegistry,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    render_prometheus_with_context(server_options, registry, |context, registry| async move {
+        registry
+            .collect_all(&context)
+            .await
+            .map_err(|err| Box::new(BadRequest(err.to_string())) as Box<dyn Error + Send + Sync>)
+    })
+    .await
+}
+
+#[cfg(all(test, feature = "hyper_server"))]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn server_options() -> ServerOptions {
+        ServerOptions::new(([127, 0, 0, 1], 0).into(), Authorization::None)
+    }
+
+    fn request(method: Method, uri: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    /// Serves `req` with a collector answering `output` and counting its
+    /// calls in `calls`.
+    async fn serve(
+        state: &Arc<ServerState>,
+        req: Request<Body>,
+        output: &str,
+        calls: &Arc<AtomicUsize>,
+    ) -> Response<Body> {
+        serve_from(state, ([10, 0, 0, 1], 51234).into(), req, output, calls).await
+    }
+
+    async fn serve_from(
+        state: &Arc<ServerState>,
+        peer: SocketAddr,
+        req: Request<Body>,
+        output: &str,
+        calls: &Arc<AtomicUsize>,
+    ) -> Response<Body> {
+        let (output, calls) = (output.to_owned(), calls.clone());
+        serve_function(
+            state.clone(),
+            Some(peer),
+            req,
+            move |_, _| async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Ok::<_, Box<dyn Error + Send + Sync>>(output)
+            },
+            Arc::new(()),
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn body(response: Response<Body>) -> String {
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_head() {
+        let state = Arc::new(ServerState::new(
+            server_options()
+                .with_cache_ttl(Duration::from_secs(60))
+                .with_etag(true),
+        ));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let head = serve(
+            &state,
+            request(Method::HEAD, "/metrics"),
+            "pippo 1\n",
+            &calls,
+        )
+        .await;
+        assert_eq!(head.status(), StatusCode::OK);
+        assert!(head.headers().get(ETAG).is_none());
+        assert_eq!(body(head).await, "");
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        let get = serve(
+            &state,
+            request(Method::GET, "/metrics"),
+            "pippo 1\n",
+            &calls,
+        )
+        .await;
+        let etag = get.headers().get(ETAG).unwrap().clone();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let head = serve(
+            &state,
+            request(Method::HEAD, "/metrics"),
+            "pippo 1\n",
+            &calls,
+        )
+        .await;
+        assert_eq!(head.headers().get(ETAG), Some(&etag));
+
+        let mut conditional = request(Method::HEAD, "/metrics");
+        conditional
+            .headers_mut()
+            .insert(IF_NONE_MATCH, etag.clone());
+        let head = serve(&state, conditional, "pippo 1\n", &calls).await;
+        assert_eq!(head.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_openmetrics_trailer() {
+        let state = Arc::new(ServerState::new(
+            server_options()
+                .with_openmetrics(true)
+                .with_cache_ttl(Duration::from_secs(60)),
+        ));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let openmetrics = || {
+            Request::builder()
+                .uri("/metrics")
+                .header(ACCEPT, "application/openmetrics-text; version=1.0.0")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        serve(&state, openmetrics(), "pippo 1\n", &calls).await;
+        let response = serve(&state, openmetrics(), "pippo 1\n", &calls).await;
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            ExpositionFormat::OpenMetrics.content_type()
+        );
+
+        let body = body(response).await;
+        assert!(body.starts_with("pippo 1\n"));
+        assert!(body.contains("exporter_cache_age_seconds "));
+        assert!(body.contains(
+            "# TYPE exporter_http_requests counter\n\
+            exporter_http_requests_total{code=\"200\",path=\"/metrics\"} 1\n"
+        ));
+        assert!(body.contains("# TYPE exporter_panics counter\nexporter_panics_total "));
+        assert!(!body.contains("# TYPE exporter_http_requests_total"));
+        assert!(body.ends_with("\n# EOF\n"));
+        assert_eq!(body.matches("# EOF").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_method_not_allowed() {
+        let state = Arc::new(ServerState::new(server_options()));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let response = serve(&state, request(Method::POST, "/metrics"), "", &calls).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[ALLOW], "GET, HEAD");
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_etag() {
+        let state = Arc::new(ServerState::new(
+            server_options()
+                .with_cache_ttl(Duration::from_secs(60))
+                .with_etag(true),
+        ));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let response = serve(
+            &state,
+            request(Method::GET, "/metrics"),
+            "pippo 1\n",
+            &calls,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[ETAG].clone();
+        let first = body(response).await;
+        assert!(first.starts_with("pippo 1\n"));
+        assert!(!first.contains("exporter_cache_age_seconds"));
+        assert!(!first.contains("exporter_http_requests_total"));
+
+        // the same tag is always the same body
+        let response = serve(
+            &state,
+            request(Method::GET, "/metrics"),
+            "pippo 1\n",
+            &calls,
+        )
+        .await;
+        assert_eq!(response.headers()[ETAG], etag);
+        assert_eq!(body(response).await, first);
+
+        let mut conditional = request(Method::GET, "/metrics");
+        conditional.headers_mut().insert(IF_NONE_MATCH, etag);
+        let response = serve(&state, conditional, "pippo 1\n", &calls).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(body(response).await, "");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_access_control() {
+        let state = Arc::new(ServerState::new(server_options().with_access_control(
+            AccessControl::new().with_allow("192.168.0.0/16".parse().unwrap()),
+        )));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let response = serve(&state, request(Method::GET, "/metrics"), "", &calls).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let state = Arc::new(ServerState::new(
+            server_options().with_rate_limit(RateLimit::new(0.5, 1)),
+        ));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let response = serve(&state, request(Method::GET, "/metrics"), "", &calls).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = serve(&state, request(Method::GET, "/metrics"), "", &calls).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "2");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_user() {
+        let state = Arc::new(ServerState::new(
+            ServerOptions::new(
+                ([127, 0, 0, 1], 0).into(),
+                Authorization::Basic("secret".to_owned()),
+            )
+            .with_rate_limit(RateLimit::new(0.5, 1).with_key(RateLimitKey::User)),
+        ));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let authorized = || {
+            let mut req = request(Method::GET, "/metrics");
+            req.headers_mut().insert(
+                AUTHORIZATION,
+                format!("Basic {}", base64::encode(":secret"))
+                    .parse()
+                    .unwrap(),
+            );
+            req
+        };
+
+        // the users are empty, each client keeps its own bucket
+        for peer in &[([10, 0, 0, 1], 51234), ([10, 0, 0, 2], 51234)] {
+            let response = serve_from(&state, (*peer).into(), authorized(), "", &calls).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = serve(&state, authorized(), "", &calls).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_unauthorized() {
+        let state = Arc::new(ServerState::new(
+            ServerOptions::new(
+                ([127, 0, 0, 1], 0).into(),
+                Authorization::Basic("secret".to_owned()),
+            )
+            .with_rate_limit(RateLimit::new(0.5, 2).with_key(RateLimitKey::User)),
+        ));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let guess = |password: &str| {
+            let mut req = request(Method::GET, "/metrics");
+            req.headers_mut().insert(
+                AUTHORIZATION,
+                format!("Basic {}", base64::encode(format!(":{}", password)))
+                    .parse()
+                    .unwrap(),
+            );
+            req
+        };
+
+        for password in &["a", "b"] {
+            let response = serve(&state, guess(password), "", &calls).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        let response = serve(&state, guess("c"), "", &calls).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        // even the right password has to wait
+        let response = serve(&state, guess("secret"), "", &calls).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let state = Arc::new(ServerState::new(
+            server_options()
+                .with_self_metrics(false)
+                .with_compression_min_size(512),
+        ));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let gzip = || {
+            let mut req = request(Method::GET, "/metrics");
+            req.headers_mut()
+                .insert(ACCEPT_ENCODING, "gzip".parse().unwrap());
+            req
+        };
+
+        let response = serve(&state, gzip(), &"pippo 1\n".repeat(64), &calls).await;
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[VARY], "accept-encoding");
+
+        let response = serve(&state, gzip(), "pippo 1\n", &calls).await;
+        assert!(response.headers().get(CONTENT_ENCODING).is_none());
+        assert!(body(response).await.starts_with("pippo 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_collector_panic() {
+        let state = Arc::new(ServerState::new(server_options()));
+
+        let response = serve_function(
+            state,
+            Some(([10, 0, 0, 1], 51234).into()),
+            request(Method::GET, "/metrics"),
+            |_, _| async move {
+                if true {
+                    panic!("boom");
+                }
+                Ok::<_, Box<dyn Error + Send + Sync>>(String::new())
+            },
+            Arc::new(()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body(response).await, "the collector panicked");
+    }
+
+    #[test]
+    fn test_forwarded_for() {
+        let options = server_options().with_access_control(
+            AccessControl::new().with_trusted_proxy("10.0.0.0/24".parse().unwrap()),
+        );
+        let mut req = request(Method::GET, "/metrics");
+        assert_eq!(forwarded_for(&req), None);
+
+        // the second proxy appended its own line
+        req.headers_mut()
+            .append("x-forwarded-for", "6.6.6.6, 1.2.3.4".parse().unwrap());
+        req.headers_mut()
+            .append("x-forwarded-for", "10.0.0.2".parse().unwrap());
+        assert_eq!(
+            forwarded_for(&req).as_deref(),
+            Some("6.6.6.6, 1.2.3.4,10.0.0.2")
+        );
+
+        let requester = requester(&options, Some(([10, 0, 0, 1], 51234).into()), &req);
+        assert_eq!(requester.client, Some([1, 2, 3, 4].into()));
+    }
+
+    #[test]
+    fn test_scrape_key() {
+        let key = |uri| scrape_key(&request(Method::GET, uri), ExpositionFormat::Text, None);
+
+        assert_eq!(
+            key("/metrics?collect[]=b&collect[]=a&collect[]=b"),
+            key("/metrics?collect[]=a&collect[]=b&nocache=1")
+        );
+        assert_ne!(key("/metrics?collect[]=a"), key("/metrics"));
+    }
 }
@@ -0,0 +1,411 @@
+use crate::ListenAddr;
+use hyper::server::conn::AddrIncoming;
+use std::error::Error;
+#[cfg(feature = "tls")]
+use {crate::tls::TlsIncoming, tokio_rustls::TlsAcceptor};
+#[cfg(unix)]
+use {
+    hyper::server::accept::Accept,
+    std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    std::os::unix::io::{FromRawFd, IntoRawFd, RawFd},
+    std::path::Path,
+    std::pin::Pin,
+    std::sync::atomic::{AtomicBool, Ordering},
+    std::task::{Context, Poll},
+    std::{env, fs, io, net, os::unix::net as unix_net, process},
+    tokio::net::{TcpListener, UnixListener, UnixStream},
+};
+
+/// The first file descriptor passed by systemd, see `sd_listen_fds(3)`.
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Set once the passed sockets are taken, they can only have one owner.
+#[cfg(unix)]
+static ACTIVATED: AtomicBool = AtomicBool::new(false);
+
+/// A bound listener, ready to accept connections.
+pub(crate) enum Incoming {
+    Tcp(AddrIncoming),
+    #[cfg(feature = "tls")]
+    Tls(TlsIncoming),
+    #[cfg(unix)]
+    Unix(UnixIncoming),
+}
+
+impl Incoming {
+    /// Serves HTTPS on the TCP listener.
+    #[cfg(feature = "tls")]
+    pub(crate) fn with_tls(self, acceptor: &TlsAcceptor) -> Self {
+        match self {
+            Incoming::Tcp(incoming) => Incoming::Tls(TlsIncoming::new(incoming, acceptor.clone())),
+            incoming => incoming,
+        }
+    }
+}
+
+pub(crate) fn bind(listen_addr: &ListenAddr) -> Result<Incoming, Box<dyn Error + Send + Sync>> {
+    match listen_addr {
+        ListenAddr::Tcp(addr) => Ok(Incoming::Tcp(AddrIncoming::bind(addr)?)),
+        #[cfg(unix)]
+        ListenAddr::Unix { path, mode } => {
+            // a socket left behind by a previous run would make the bind
+            // fail, one still accepting connections belongs to a live server
+            match fs::symlink_metadata(path) {
+                Ok(metadata) if metadata.file_type().is_socket() => {
+                    match unix_net::UnixStream::connect(path) {
+                        Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                            fs::remove_file(path)?
+                        }
+                        _ => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::AddrInUse,
+                                format!("{} is in use by another server", path.display()),
+                            )
+                            .into())
+                        }
+                    }
+                }
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("{} exists and is not a socket", path.display()),
+                    )
+                    .into())
+                }
+                Err(_) => {}
+            }
+
+            let listener = match mode {
+                Some(mode) => bind_with_mode(path, *mode)?,
+                None => UnixListener::bind(path)?,
+            };
+
+            Ok(Incoming::Unix(UnixIncoming { listener }))
+        }
+    }
+}
+
+/// Binds a Unix socket reachable only once its permissions are `mode`:
+/// it is bound in a private directory next to `path`, then linked at
+/// `path`. Unlike a rename, the link never replaces a file created in
+/// the meantime.
+#[cfg(unix)]
+fn bind_with_mode(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let private = path.with_file_name(format!(".{}.{}", file_name, process::id()));
+    let socket = private.join("socket");
+    // left behind by a crashed run with the same pid, like in containers
+    let _ = fs::remove_file(&socket);
+    let _ = fs::remove_dir(&private);
+
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = UnixListener::bind(&socket).and_then(|listener| {
+        fs::set_permissions(&socket, fs::Permissions::from_mode(mode))?;
+        fs::hard_link(&socket, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&socket);
+    let _ = fs::remove_dir(&private);
+
+    bound
+}
+
+/// A listening socket passed by systemd.
+#[cfg(unix)]
+pub(crate) struct ActivatedSocket {
+    pub(crate) incoming: Incoming,
+    pub(crate) addr: String,
+    /// The name from `FileDescriptorName=` in the socket unit.
+    pub(crate) name: String,
+}
+
+/// Takes the listening sockets passed by systemd socket activation. The
+/// result is empty if the process was not socket activated or if the
+/// sockets were already taken. The environment is left untouched as
+/// changing it is not safe once other threads run: child processes
+/// ignore the variables anyway since `LISTEN_PID` is not theirs, and
+/// the sockets are closed on exec.
+#[cfg(unix)]
+pub(crate) fn activated() -> Result<Vec<ActivatedSocket>, Box<dyn Error + Send + Sync>> {
+    let var = |name| env::var(name).ok();
+    let fds = parse_listen_fds(
+        var("LISTEN_PID").as_deref(),
+        var("LISTEN_FDS").as_deref(),
+        var("LISTEN_FDNAMES").as_deref(),
+        process::id(),
+    )?;
+    if fds.is_empty() || ACTIVATED.swap(true, Ordering::SeqCst) {
+        return Ok(Vec::new());
+    }
+
+    fds.into_iter()
+        .map(|(fd, name)| {
+            let (incoming, addr) = from_fd(fd)
+                .map_err(|err| format!("cannot use the systemd socket {}: {}", name, err))?;
+            Ok(ActivatedSocket {
+                incoming,
+                addr,
+                name,
+            })
+        })
+        .collect()
+}
+
+/// Parses the socket activation variables, returning the passed file
+/// descriptors and their names. They are ignored if meant for another
+/// process than `pid`.
+#[cfg(unix)]
+fn parse_listen_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    listen_fdnames: Option<&str>,
+    pid: u32,
+) -> Result<Vec<(RawFd, String)>, Box<dyn Error + Send + Sync>> {
+    if listen_pid.and_then(|listen_pid| listen_pid.parse::<u32>().ok()) != Some(pid) {
+        return Ok(Vec::new());
+    }
+    let count = match listen_fds {
+        Some(listen_fds) => listen_fds
+            .parse::<RawFd>()
+            .map_err(|_| format!("invalid LISTEN_FDS {:?}", listen_fds))?,
+        None => return Ok(Vec::new()),
+    };
+    let names = listen_fdnames
+        .map(|names| names.split(':').collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    Ok((0..count)
+        .map(|n| {
+            // systemd names the sockets "unknown" unless told otherwise
+            let name = names.get(n as usize).copied().unwrap_or("unknown");
+            (LISTEN_FDS_START + n, name.to_owned())
+        })
+        .collect())
+}
+
+/// Reads an integer socket option of `fd`.
+#[cfg(unix)]
+fn socket_option(fd: RawFd, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == 0 {
+        Ok(value)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Turns a passed listening socket, either TCP or Unix, into an [`Incoming`].
+/// Anything but a listening stream socket is refused and left open.
+#[cfg(unix)]
+fn from_fd(fd: RawFd) -> Result<(Incoming, String), Box<dyn Error + Send + Sync>> {
+    if socket_option(fd, libc::SO_TYPE)? != libc::SOCK_STREAM {
+        return Err(format!("file descriptor {} is not a stream socket", fd).into());
+    }
+    if socket_option(fd, libc::SO_ACCEPTCONN)? == 0 {
+        return Err(format!("file descriptor {} is not listening", fd).into());
+    }
+    // like sd_listen_fds(3), keep the sockets out of the child processes
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    // systemd gives us the ownership of the sockets it passes
+    let listener = unsafe { net::TcpListener::from_raw_fd(fd) };
+    match listener.local_addr() {
+        Ok(addr) => {
+            listener.set_nonblocking(true)?;
+            let incoming = AddrIncoming::from_listener(TcpListener::from_std(listener)?)?;
+            Ok((Incoming::Tcp(incoming), format!("http://{}", addr)))
+        }
+        // the address of a Unix socket is not an inet one
+        Err(_) => {
+            let listener = unsafe { unix_net::UnixListener::from_raw_fd(listener.into_raw_fd()) };
+            let addr = listener.local_addr()?;
+            listener.set_nonblocking(true)?;
+            let listener = UnixListener::from_std(listener)?;
+            let addr = match addr.as_pathname() {
+                Some(path) => format!("unix:{}", path.display()),
+                None => "unix:(unnamed)".to_owned(),
+            };
+            Ok((Incoming::Unix(UnixIncoming { listener }), addr))
+        }
+    }
+}
+
+#[cfg(unix)]
+pub(crate) struct UnixIncoming {
+    listener: UnixListener,
+}
+
+#[cfg(unix)]
+impl Accept for UnixIncoming {
+    type Conn = UnixStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.listener
+            .poll_accept(cx)
+            .map(|accepted| Some(accepted.map(|(stream, _)| stream)))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    #[tokio::test]
+    async fn test_bind_unix() {
+        let path = std::env::temp_dir().join(format!("exporter-{}.sock", std::process::id()));
+        let listen_addr = ListenAddr::Unix {
+            path: path.clone(),
+            mode: Some(0o600),
+        };
+
+        // binding twice replaces the stale socket
+        for _ in 0..2 {
+            assert!(matches!(bind(&listen_addr), Ok(Incoming::Unix(_))));
+            let metadata = fs::metadata(&path).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+        let private = format!(
+            ".exporter-{}.sock.{}",
+            std::process::id(),
+            std::process::id()
+        );
+        assert!(!std::env::temp_dir().join(private).exists());
+
+        // but not the socket of a running server
+        let running = bind(&listen_addr).unwrap();
+        let err = bind(&listen_addr).err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<io::Error>().unwrap().kind(),
+            io::ErrorKind::AddrInUse
+        );
+        drop(running);
+        fs::remove_file(&path).unwrap();
+
+        // other files are never replaced
+        let regular_file =
+            std::env::temp_dir().join(format!("exporter-{}.txt", std::process::id()));
+        fs::write(&regular_file, "not a socket").unwrap();
+        for mode in &[None, Some(0o600)] {
+            assert!(bind(&ListenAddr::Unix {
+                path: regular_file.clone(),
+                mode: *mode,
+            })
+            .is_err());
+            assert_eq!(fs::read_to_string(&regular_file).unwrap(), "not a socket");
+        }
+        fs::remove_file(&regular_file).unwrap();
+    }
+
+    #[test]
+    fn test_parse_listen_fds() {
+        assert!(parse_listen_fds(None, None, None, 42).unwrap().is_empty());
+        assert!(parse_listen_fds(Some("41"), Some("2"), None, 42)
+            .unwrap()
+            .is_empty());
+        assert!(parse_listen_fds(Some("42"), Some("two"), None, 42).is_err());
+
+        assert_eq!(
+            parse_listen_fds(Some("42"), Some("2"), Some("metrics"), 42).unwrap(),
+            vec![(3, "metrics".to_owned()), (4, "unknown".to_owned())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_from_fd() {
+        let tcp = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let (incoming, description) = from_fd(tcp.into_raw_fd()).unwrap();
+        assert!(matches!(incoming, Incoming::Tcp(_)));
+        assert_eq!(description, format!("http://{}", addr));
+
+        let path = std::env::temp_dir().join(format!("exporter-fd-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let unix = unix_net::UnixListener::bind(&path).unwrap();
+        let (incoming, description) = from_fd(unix.into_raw_fd()).unwrap();
+        assert!(matches!(incoming, Incoming::Unix(_)));
+        assert_eq!(description, format!("unix:{}", path.display()));
+        fs::remove_file(&path).unwrap();
+
+        // refused sockets stay with their owner
+        let udp = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(from_fd(udp.as_raw_fd()).is_err());
+        let tcp = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = net::TcpStream::connect(tcp.local_addr().unwrap()).unwrap();
+        assert!(from_fd(stream.as_raw_fd()).is_err());
+        let file = fs::File::open("/dev/null").unwrap();
+        assert!(from_fd(file.as_raw_fd()).is_err());
+    }
+
+    /// Runs itself again as a socket activated process, the listener
+    /// passed on file descriptor 3.
+    #[tokio::test]
+    async fn test_activated() {
+        if let Ok(addr) = env::var("EXPORTER_TEST_ACTIVATED_ADDR") {
+            let sockets = activated().unwrap();
+            assert_eq!(sockets.len(), 1);
+            assert!(matches!(sockets[0].incoming, Incoming::Tcp(_)));
+            assert_eq!(sockets[0].addr, format!("http://{}", addr));
+            assert_eq!(sockets[0].name, "metrics");
+            // the variables are still there but the sockets are taken
+            assert_eq!(env::var("LISTEN_FDS").as_deref(), Ok("1"));
+            assert!(activated().unwrap().is_empty());
+            return;
+        }
+
+        let tcp = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let fd = tcp.as_raw_fd();
+        let mut child = std::process::Command::new("sh");
+        child
+            .arg("-c")
+            // exec keeps the pid of the shell
+            .arg("LISTEN_PID=$$ exec \"$0\" \"$@\"")
+            .arg(env::current_exe().unwrap())
+            .args(["listener::tests::test_activated", "--exact"])
+            .env("LISTEN_FDS", "1")
+            .env("LISTEN_FDNAMES", "metrics")
+            .env("EXPORTER_TEST_ACTIVATED_ADDR", addr.to_string());
+        unsafe {
+            child.pre_exec(move || {
+                // dup2 clears FD_CLOEXEC unless the descriptors are the same
+                let ret = if fd == LISTEN_FDS_START {
+                    libc::fcntl(fd, libc::F_SETFD, 0)
+                } else {
+                    libc::dup2(fd, LISTEN_FDS_START)
+                };
+                if ret == -1 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(())
+                }
+            });
+        }
+
+        let output = child.output().unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+        assert!(String::from_utf8_lossy(&output.stdout).contains("1 passed"));
+    }
+}
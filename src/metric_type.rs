@@ -70,9 +70,37 @@ string_enum!(
     Histogram,
     "histogram",
     Summary,
-    "summary"
+    "summary",
+    Info,
+    "info",
+    StateSet,
+    "stateset",
+    Unknown,
+    "unknown"
 );
 
+impl MetricType {
+    /// The type as the classic text format knows it: info and state set
+    /// metrics are gauges there and unknown ones are untyped.
+    pub fn classic_name(&self) -> &str {
+        match self {
+            MetricType::Info | MetricType::StateSet => "gauge",
+            MetricType::Unknown => "untyped",
+            _ => self.as_ref(),
+        }
+    }
+
+    /// Whether OpenMetrics allows exemplars on the samples of this type
+    /// with `suffix`: all the counter ones and the histogram buckets.
+    pub(crate) fn allows_exemplars(&self, suffix: &str) -> bool {
+        match self {
+            MetricType::Counter => true,
+            MetricType::Histogram => suffix == "_bucket",
+            _ => false,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -86,6 +114,24 @@ mod tests {
         assert_eq!("histogram", MetricType::Histogram.as_ref());
     }
 
+    #[test]
+    fn test_classic_name() {
+        assert_eq!("counter", MetricType::Counter.classic_name());
+        assert_eq!("gauge", MetricType::Info.classic_name());
+        assert_eq!("gauge", MetricType::StateSet.classic_name());
+        assert_eq!("untyped", MetricType::Unknown.classic_name());
+    }
+
+    #[test]
+    fn test_allows_exemplars() {
+        assert!(MetricType::Counter.allows_exemplars(""));
+        assert!(MetricType::Counter.allows_exemplars("_total"));
+        assert!(MetricType::Histogram.allows_exemplars("_bucket"));
+        assert!(!MetricType::Histogram.allows_exemplars("_sum"));
+        assert!(!MetricType::Gauge.allows_exemplars("_total"));
+        assert!(!MetricType::Summary.allows_exemplars("_total"));
+    }
+
     #[test]
     fn test_display() {
         assert_eq!("gauge", format!("{}", MetricType::Gauge));
@@ -97,5 +143,9 @@ mod tests {
             MetricType::Histogram,
             MetricType::try_from("histogram").unwrap()
         );
+        assert_eq!(
+            MetricType::StateSet,
+            MetricType::try_from("stateset").unwrap()
+        );
     }
 }
@@ -0,0 +1,166 @@
+use crate::{Collection, MetricType, PrometheusInstance, PrometheusMetric};
+use log::warn;
+use std::error::Error;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The output of a successful background collection.
+#[derive(Debug, Clone)]
+pub(crate) struct Snapshot {
+    pub(crate) collection: Arc<Collection>,
+    pub(crate) collected_at: SystemTime,
+}
+
+impl Snapshot {
+    /// Renders the snapshot followed by the time it was collected at.
+    pub(crate) fn render(&self) -> String {
+        let timestamp = self
+            .collected_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        let mut s = self.collection.render();
+        s.push_str(
+            &PrometheusMetric::build()
+                .with_name("exporter_snapshot_timestamp_seconds")
+                .with_metric_type(MetricType::Gauge)
+                .with_help("UNIX time of the collection served by this scrape")
+                .build()
+                .render_and_append_instance(&PrometheusInstance::new().with_value(timestamp))
+                .render(),
+        );
+        s
+    }
+}
+
+/// Holds the latest successful snapshot.
+#[derive(Debug, Default)]
+pub(crate) struct SnapshotStore {
+    latest: RwLock<Option<Snapshot>>,
+}
+
+impl SnapshotStore {
+    pub(crate) fn latest(&self) -> Option<Snapshot> {
+        self.latest.read().expect("snapshot lock poisoned").clone()
+    }
+
+    fn store(&self, collection: Collection) {
+        *self.latest.write().expect("snapshot lock poisoned") = Some(Snapshot {
+            collection: Arc::new(collection),
+            collected_at: SystemTime::now(),
+        });
+    }
+}
+
+/// How long to wait before the next collection given the number
+/// of consecutive failures so far.
+pub(crate) fn next_delay(interval: Duration, max_backoff: Duration, failures: u32) -> Duration {
+    if failures == 0 {
+        return interval;
+    }
+
+    interval
+        .checked_mul(2u32.saturating_pow(failures.min(31)))
+        .map_or(max_backoff, |delay| delay.min(max_backoff))
+        .max(interval)
+}
+
+/// Calls `collect` forever, storing every successful output in `store`.
+pub(crate) async fn poll<C, Fut, R>(
+    store: &SnapshotStore,
+    interval: Duration,
+    max_backoff: Duration,
+    mut collect: C,
+) where
+    C: FnMut() -> Fut,
+    Fut: Future<Output = Result<R, Box<dyn Error + Send + Sync>>>,
+    R: Into<Collection>,
+{
+    let mut failures = 0u32;
+
+    loop {
+        match collect().await.map(Into::into) {
+            Ok(mut collection) => {
+                collection.drain().await;
+                for err in &collection.errors {
+                    warn!("background collection error == {:?}", err);
+                }
+
+                failures = 0;
+                store.store(collection);
+            }
+            Err(err) => {
+                failures = failures.saturating_add(1);
+                warn!(
+                    "background collection failed ({} in a row) == {:?}",
+                    failures, err
+                );
+            }
+        }
+
+        tokio::time::sleep(next_delay(interval, max_backoff, failures)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_delay() {
+        let interval = Duration::from_secs(10);
+        let max_backoff = Duration::from_secs(60);
+
+        assert_eq!(next_delay(interval, max_backoff, 0), interval);
+        assert_eq!(
+            next_delay(interval, max_backoff, 1),
+            Duration::from_secs(20)
+        );
+        assert_eq!(
+            next_delay(interval, max_backoff, 2),
+            Duration::from_secs(40)
+        );
+        assert_eq!(next_delay(interval, max_backoff, 3), max_backoff);
+        assert_eq!(next_delay(interval, max_backoff, u32::MAX), max_backoff);
+        // the backoff never shortens the interval
+        assert_eq!(next_delay(interval, Duration::from_secs(1), 1), interval);
+    }
+
+    #[tokio::test]
+    async fn test_poll_keeps_last_success() {
+        let store = Arc::new(SnapshotStore::default());
+        let mut calls = 0;
+
+        let poller = {
+            let store = store.clone();
+            tokio::spawn(async move {
+                poll(
+                    &store,
+                    Duration::from_millis(10),
+                    Duration::from_millis(10),
+                    move || {
+                        calls += 1;
+                        let outcome: Result<String, Box<dyn Error + Send + Sync>> = if calls == 1 {
+                            Ok("pippo 1\n".to_owned())
+                        } else {
+                            Err("backend down".into())
+                        };
+                        async move { outcome }
+                    },
+                )
+                .await
+            })
+        };
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        poller.abort();
+
+        let snapshot = store.latest().unwrap();
+        assert_eq!(snapshot.collection.metrics, "pippo 1\n");
+        let rendered = snapshot.render();
+        assert!(rendered.starts_with("pippo 1\n# HELP exporter_scrape_errors"));
+        assert!(rendered.contains("# HELP exporter_snapshot_timestamp_seconds"));
+    }
+}
@@ -1,5 +1,20 @@
 #[cfg(feature = "hyper_server")]
-pub use crate::render_prometheus;
-#[cfg(feature = "hyper_server")]
 pub use crate::server_options::*;
-pub use crate::{MetricType, PrometheusInstance, PrometheusMetric};
+#[cfg(all(feature = "hyper_server", target_os = "linux"))]
+pub use crate::ProcessCollector;
+#[cfg(feature = "tls")]
+pub use crate::TlsOptions;
+#[cfg(feature = "hyper_server")]
+pub use crate::{
+    async_trait, AccessControl, AccessLogFormat, BadRequest, Collection, Collector,
+    CollectorRegistry, CollectorSelectionError, InvalidIpNet, IpNet, MetricsSender, Probe,
+    RateLimit, RateLimitKey, ScrapeAborted, ScrapeContext,
+};
+pub use crate::{
+    build_info, BuildInfo, Exemplar, ExemplarTooLong, ExpositionFormat, MetricType,
+    PrometheusInstance, PrometheusMetric, StateSetError,
+};
+#[cfg(feature = "hyper_server")]
+pub use crate::{
+    render_collectors, render_prometheus, render_prometheus_stream, render_prometheus_with_context,
+};
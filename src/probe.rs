@@ -0,0 +1,212 @@
+use crate::server_options::ProbeOptions;
+use crate::{MetricType, PrometheusInstance, PrometheusMetric};
+use serde_json::{json, Map, Value};
+use std::time::Duration;
+use thiserror::Error;
+
+/// A validated `/probe` request. The server inserts it in the
+/// extensions of the request handed to the collector so it
+/// knows which target (and module) to collect.
+///
+/// Example:
+///
+/// ```no_run
+/// use prometheus_exporter_base::prelude::*;
+///
+/// # async fn run(server_options: ServerOptions) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// render_prometheus(server_options, (), |request, _options| async move {
+///     match request.extensions().get::<Probe>() {
+///         Some(probe) => Ok(format!("# probing {}\n", probe.target)),
+///         None => Ok("# regular scrape\n".to_owned()),
+///     }
+/// })
+/// .await
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Probe {
+    pub target: String,
+    pub module: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ProbeError {
+    #[error("the target query parameter is mandatory")]
+    MissingTarget,
+    #[error("target {0} is not allowed")]
+    TargetNotAllowed(String),
+    #[error("module {0} is not allowed")]
+    ModuleNotAllowed(String),
+}
+
+impl Probe {
+    /// Parses the `target` and `module` query parameters, validating
+    /// them against the allowed ones. If no module is passed the first
+    /// allowed module, if any, is used.
+    pub fn from_query(
+        query: Option<&str>,
+        probe_options: &ProbeOptions,
+    ) -> Result<Self, ProbeError> {
+        let mut target = None;
+        let mut module = None;
+        for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            match key.as_ref() {
+                "target" => target = Some(value.into_owned()),
+                "module" => module = Some(value.into_owned()),
+                _ => {}
+            }
+        }
+
+        let target = target.ok_or(ProbeError::MissingTarget)?;
+        if !probe_options
+            .targets
+            .iter()
+            .any(|allowed| allowed.target == target)
+        {
+            return Err(ProbeError::TargetNotAllowed(target));
+        }
+
+        let module = match module {
+            Some(module) if probe_options.modules.contains(&module) => Some(module),
+            Some(module) => return Err(ProbeError::ModuleNotAllowed(module)),
+            None => probe_options.modules.first().cloned(),
+        };
+
+        Ok(Self { target, module })
+    }
+}
+
+/// Renders `probe_success` and `probe_duration_seconds`. A probe
+/// succeeds if the collector neither failed nor reported errors.
+pub(crate) fn render_probe(success: bool, duration: Duration) -> String {
+    let mut s = PrometheusMetric::build()
+        .with_name("probe_success")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("Whether the probe succeeded")
+        .build()
+        .render_and_append_instance(&PrometheusInstance::new().with_value(success as u8))
+        .render();
+    s.push_str(
+        &PrometheusMetric::build()
+            .with_name("probe_duration_seconds")
+            .with_metric_type(MetricType::Gauge)
+            .with_help("How long the probe took to complete in seconds")
+            .build()
+            .render_and_append_instance(
+                &PrometheusInstance::new().with_value(duration.as_secs_f64()),
+            )
+            .render(),
+    );
+
+    s
+}
+
+/// Renders the targets as expected by Prometheus `http_sd_configs`:
+/// one target group per target, carrying its labels.
+pub(crate) fn render_service_discovery(probe_options: &ProbeOptions) -> String {
+    let groups = probe_options
+        .targets
+        .iter()
+        .map(|target| {
+            let labels = target
+                .labels
+                .iter()
+                .map(|(l, v)| (l.clone(), Value::String(v.clone())))
+                .collect::<Map<_, _>>();
+
+            json!({ "targets": [target.target], "labels": labels })
+        })
+        .collect::<Vec<_>>();
+
+    Value::Array(groups).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_options::ProbeTarget;
+
+    fn probe_options() -> ProbeOptions {
+        ProbeOptions::new()
+            .with_target(ProbeTarget::new("https://example.com/a b"))
+            .with_target(ProbeTarget::new("10.0.0.1:161"))
+            .with_module("http_2xx")
+            .with_module("icmp")
+    }
+
+    #[test]
+    fn test_from_query() {
+        assert_eq!(
+            Probe::from_query(Some("target=10.0.0.1%3A161&module=icmp"), &probe_options()),
+            Ok(Probe {
+                target: "10.0.0.1:161".to_owned(),
+                module: Some("icmp".to_owned()),
+            })
+        );
+        assert_eq!(
+            Probe::from_query(
+                Some("target=https%3A%2F%2Fexample.com%2Fa+b"),
+                &probe_options()
+            ),
+            Ok(Probe {
+                target: "https://example.com/a b".to_owned(),
+                module: Some("http_2xx".to_owned()),
+            })
+        );
+    }
+
+    #[test]
+    fn test_from_query_rejected() {
+        assert_eq!(
+            Probe::from_query(None, &probe_options()),
+            Err(ProbeError::MissingTarget)
+        );
+        assert_eq!(
+            Probe::from_query(Some("target=10.0.0.2"), &probe_options()),
+            Err(ProbeError::TargetNotAllowed("10.0.0.2".to_owned()))
+        );
+        assert_eq!(
+            Probe::from_query(Some("target=10.0.0.1:161&module=tcp"), &probe_options()),
+            Err(ProbeError::ModuleNotAllowed("tcp".to_owned()))
+        );
+        assert_eq!(
+            Probe::from_query(
+                Some("target=10.0.0.1:161&module=icmp"),
+                &ProbeOptions::new().with_target(ProbeTarget::new("10.0.0.1:161"))
+            ),
+            Err(ProbeError::ModuleNotAllowed("icmp".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_render_probe() {
+        assert_eq!(
+            render_probe(false, Duration::from_millis(250)),
+            "# HELP probe_success Whether the probe succeeded\n\
+            # TYPE probe_success gauge\n\
+            probe_success 0\n\
+            # HELP probe_duration_seconds How long the probe took to complete in seconds\n\
+            # TYPE probe_duration_seconds gauge\n\
+            probe_duration_seconds 0.25\n"
+        );
+        assert!(render_probe(true, Duration::from_secs(1)).contains("probe_success 1\n"));
+    }
+
+    #[test]
+    fn test_render_service_discovery() {
+        let probe_options = ProbeOptions::new()
+            .with_target(
+                ProbeTarget::new("10.0.0.1:161")
+                    .with_label("site", "milan")
+                    .with_label("rack", "a1"),
+            )
+            .with_target(ProbeTarget::new("10.0.0.2:161"));
+
+        assert_eq!(
+            render_service_discovery(&probe_options),
+            "[{\"labels\":{\"rack\":\"a1\",\"site\":\"milan\"},\"targets\":[\"10.0.0.1:161\"]},\
+            {\"labels\":{},\"targets\":[\"10.0.0.2:161\"]}]"
+        );
+        assert_eq!(render_service_discovery(&ProbeOptions::new()), "[]");
+    }
+}
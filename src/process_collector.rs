@@ -0,0 +1,250 @@
+use crate::self_metrics::counter;
+use crate::{
+    Collector, ExpositionFormat, MetricType, PrometheusInstance, PrometheusMetric, ScrapeContext,
+};
+use async_trait::async_trait;
+use std::error::Error;
+use std::fs;
+
+/// The kernel reports CPU times in clock ticks, which are 100 per
+/// second on every Linux architecture user space sees.
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+
+/// Collects the standard `process_*` metrics of the current process
+/// from `/proc/self`, like the official Go client does.
+///
+/// Enable it on the hyper server with
+/// [`ServerOptions::with_process_metrics`] or register it in a
+/// [`CollectorRegistry`].
+///
+/// [`ServerOptions::with_process_metrics`]: struct.ServerOptions.html#method.with_process_metrics
+/// [`CollectorRegistry`]: struct.CollectorRegistry.html
+///
+/// # Examples
+///
+/// ```
+/// use prometheus_exporter_base::prelude::*;
+///
+/// let registry = CollectorRegistry::new().with_collector("process", ProcessCollector::new());
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessCollector {}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ProcessStats {
+    cpu_seconds: f64,
+    start_time_seconds: f64,
+    threads: u64,
+    virtual_memory_bytes: u64,
+    resident_memory_bytes: u64,
+    open_fds: u64,
+    max_fds: Option<u64>,
+}
+
+impl ProcessCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads `/proc` and renders the metrics.
+    pub(crate) fn render(
+        &self,
+        format: ExpositionFormat,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let boot_time = parse_boot_time(&fs::read_to_string("/proc/stat")?)
+            .ok_or("cannot find the boot time in /proc/stat")?;
+        let mut stats = parse_stat(&fs::read_to_string("/proc/self/stat")?, boot_time)
+            .ok_or("cannot parse /proc/self/stat")?;
+        stats.resident_memory_bytes =
+            parse_resident_memory(&fs::read_to_string("/proc/self/status")?)
+                .ok_or("cannot find VmRSS in /proc/self/status")?;
+        stats.open_fds = fs::read_dir("/proc/self/fd")?.count() as u64;
+        stats.max_fds = parse_max_fds(&fs::read_to_string("/proc/self/limits")?);
+
+        Ok(render_stats(&stats, format))
+    }
+}
+
+#[async_trait]
+impl Collector for ProcessCollector {
+    async fn collect(
+        &self,
+        context: &ScrapeContext,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        self.render(context.format)
+    }
+}
+
+/// Parses the `btime` line of `/proc/stat`.
+fn parse_boot_time(proc_stat: &str) -> Option<u64> {
+    proc_stat
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))
+        .and_then(|btime| btime.trim().parse().ok())
+}
+
+/// Parses `/proc/self/stat`. The memory fields it does not carry are left zeroed.
+fn parse_stat(stat: &str, boot_time: u64) -> Option<ProcessStats> {
+    // the process name is between parentheses and may contain spaces,
+    // the remaining fields start from the third one (the state)
+    let (_, fields) = stat.rsplit_once(')')?;
+    let fields = fields.split_whitespace().collect::<Vec<_>>();
+    let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();
+
+    Some(ProcessStats {
+        cpu_seconds: (field(14)? + field(15)?) as f64 / CLOCK_TICKS_PER_SECOND,
+        start_time_seconds: boot_time as f64 + field(22)? as f64 / CLOCK_TICKS_PER_SECOND,
+        threads: field(20)?,
+        virtual_memory_bytes: field(23)?,
+        resident_memory_bytes: 0,
+        open_fds: 0,
+        max_fds: None,
+    })
+}
+
+/// Parses the `VmRSS` line of `/proc/self/status`, in bytes.
+fn parse_resident_memory(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|rss| rss.trim().strip_suffix("kB"))
+        .and_then(|kb| kb.trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+/// Parses the soft limit of open files from `/proc/self/limits`.
+/// `None` means unlimited.
+fn parse_max_fds(limits: &str) -> Option<u64> {
+    limits
+        .lines()
+        .find_map(|line| line.strip_prefix("Max open files"))
+        .and_then(|limit| limit.split_whitespace().next()?.parse().ok())
+}
+
+fn render_stats(stats: &ProcessStats, format: ExpositionFormat) -> String {
+    let gauge = |name: &str, help: &str, value: f64| {
+        PrometheusMetric::build()
+            .with_name(name)
+            .with_metric_type(MetricType::Gauge)
+            .with_help(help)
+            .with_format(format)
+            .build()
+            .render_and_append_instance(&PrometheusInstance::new().with_value(value))
+            .render()
+    };
+
+    let (mut cpu_seconds, suffix) = counter(
+        "process_cpu_seconds_total",
+        "Total user and system CPU time spent in seconds",
+        format,
+    );
+    let mut s = cpu_seconds
+        .render_and_append_instance(
+            &PrometheusInstance::new()
+                .with_suffix(suffix)
+                .with_value(stats.cpu_seconds),
+        )
+        .render();
+    s.push_str(&gauge(
+        "process_start_time_seconds",
+        "Start time of the process since unix epoch in seconds",
+        stats.start_time_seconds,
+    ));
+    s.push_str(&gauge(
+        "process_threads",
+        "Number of OS threads in the process",
+        stats.threads as f64,
+    ));
+    s.push_str(&gauge(
+        "process_virtual_memory_bytes",
+        "Virtual memory size in bytes",
+        stats.virtual_memory_bytes as f64,
+    ));
+    s.push_str(&gauge(
+        "process_resident_memory_bytes",
+        "Resident memory size in bytes",
+        stats.resident_memory_bytes as f64,
+    ));
+    s.push_str(&gauge(
+        "process_open_fds",
+        "Number of open file descriptors",
+        stats.open_fds as f64,
+    ));
+    if let Some(max_fds) = stats.max_fds {
+        s.push_str(&gauge(
+            "process_max_fds",
+            "Maximum number of open file descriptors",
+            max_fds as f64,
+        ));
+    }
+
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let stat = "4242 (my (weird) exporter) S 1 4242 4242 0 -1 4194560 1294 0 0 0 \
+                    150 75 0 0 20 0 6 0 12345 11636736 812 18446744073709551615";
+        assert_eq!(
+            parse_stat(stat, 1_600_000_000),
+            Some(ProcessStats {
+                cpu_seconds: 2.25,
+                start_time_seconds: 1_600_000_123.45,
+                threads: 6,
+                virtual_memory_bytes: 11_636_736,
+                resident_memory_bytes: 0,
+                open_fds: 0,
+                max_fds: None,
+            })
+        );
+        assert_eq!(parse_stat("4242 (truncated) S 1", 0), None);
+
+        assert_eq!(
+            parse_boot_time("cpu  1 2 3\nintr 0\nbtime 1600000000\nprocesses 10\n"),
+            Some(1_600_000_000)
+        );
+        assert_eq!(
+            parse_resident_memory("Name:\texporter\nVmRSS:\t    3248 kB\nThreads:\t6\n"),
+            Some(3_325_952)
+        );
+        assert_eq!(
+            parse_max_fds(
+                "Limit                     Soft Limit           Hard Limit           Units     \n\
+                 Max open files            1024                 524288               files     \n"
+            ),
+            Some(1024)
+        );
+        assert_eq!(
+            parse_max_fds(
+                "Max open files            unlimited            unlimited            files     \n"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let rendered = ProcessCollector::new()
+            .render(ExpositionFormat::Text)
+            .unwrap();
+
+        for name in &[
+            "process_cpu_seconds_total",
+            "process_start_time_seconds",
+            "process_threads",
+            "process_virtual_memory_bytes",
+            "process_resident_memory_bytes",
+            "process_open_fds",
+        ] {
+            assert!(
+                rendered.contains(&format!("\n{} ", name)),
+                "{} missing",
+                name
+            );
+        }
+    }
+}
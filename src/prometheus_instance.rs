@@ -1,21 +1,33 @@
-use crate::{RenderToPrometheus, ToAssign, Yes};
+use crate::{Exemplar, ExemplarTooLong, ExpositionFormat, RenderToPrometheus, ToAssign, Yes};
 use num::Num;
 use std::convert::Into;
 use std::marker::PhantomData;
 use std::time::{SystemTime, SystemTimeError, UNIX_EPOCH};
+use thiserror::Error;
 
 #[derive(Debug, Clone, Copy)]
 pub struct MissingValue {}
 impl ToAssign for MissingValue {}
 
+/// Returned when the states of a state set are not usable.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum StateSetError {
+    #[error("{0} is not one of the states")]
+    UnknownState(String),
+    #[error("state {0} is listed more than once")]
+    DuplicateState(String),
+}
+
 #[derive(Debug, Clone)]
 pub struct PrometheusInstance<'a, N, ValueSet>
 where
     N: Num + std::fmt::Display + std::fmt::Debug,
 {
+    suffix: &'a str,
     labels: Vec<(&'a str, &'a str)>,
     value: Option<N>,
     timestamp: Option<u128>,
+    exemplar: Option<Exemplar<'a>>,
     value_set: PhantomData<ValueSet>,
 }
 
@@ -25,14 +37,109 @@ where
 {
     pub fn new() -> Self {
         Self {
+            suffix: "",
             labels: Vec::new(),
             value: None,
             timestamp: None,
+            exemplar: None,
             value_set: PhantomData {},
         }
     }
 }
 
+impl<'a> PrometheusInstance<'a, u8, Yes> {
+    /// The only sample of an info metric: the `_info` suffix and the
+    /// value 1. The information goes in the labels.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use prometheus_exporter_base::prelude::*;
+    ///
+    /// let rendered = PrometheusMetric::build()
+    ///     .with_name("exporter_build")
+    ///     .with_metric_type(MetricType::Info)
+    ///     .with_help("The exporter build")
+    ///     .build()
+    ///     .render_and_append_instance(&PrometheusInstance::info().with_label("version", "1.4.0"))
+    ///     .render();
+    ///
+    /// assert_eq!(
+    ///     rendered,
+    ///     concat!(
+    ///         "# HELP exporter_build_info The exporter build\n",
+    ///         "# TYPE exporter_build_info gauge\n",
+    ///         "exporter_build_info{version=\"1.4.0\"} 1\n",
+    ///     )
+    /// );
+    /// ```
+    pub fn info() -> Self {
+        PrometheusInstance::new().with_suffix("_info").with_value(1)
+    }
+
+    /// The samples of the state set `name`, one per state labelled
+    /// `name="state"`. `current` is the only state set to 1, the others
+    /// are 0. Fails if `current` is not in `states` or if a state is
+    /// listed twice, which would yield two identical series.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use prometheus_exporter_base::prelude::*;
+    ///
+    /// let mut metric = PrometheusMetric::build()
+    ///     .with_name("service_state")
+    ///     .with_metric_type(MetricType::StateSet)
+    ///     .with_help("The state of the service")
+    ///     .with_format(ExpositionFormat::OpenMetrics)
+    ///     .build();
+    /// for instance in
+    ///     PrometheusInstance::state_set("service_state", &["running", "stopped"], &"running")
+    ///         .expect("unknown state")
+    /// {
+    ///     metric.render_and_append_instance(&instance.with_label("service", "db"));
+    /// }
+    ///
+    /// assert_eq!(
+    ///     metric.render(),
+    ///     concat!(
+    ///         "# HELP service_state The state of the service\n",
+    ///         "# TYPE service_state stateset\n",
+    ///         "service_state{service_state=\"running\",service=\"db\"} 1\n",
+    ///         "service_state{service_state=\"stopped\",service=\"db\"} 0\n",
+    ///     )
+    /// );
+    /// ```
+    pub fn state_set<S>(
+        name: &'a str,
+        states: &'a [S],
+        current: &S,
+    ) -> Result<Vec<Self>, StateSetError>
+    where
+        S: AsRef<str> + PartialEq,
+    {
+        if !states.contains(current) {
+            return Err(StateSetError::UnknownState(current.as_ref().to_owned()));
+        }
+        let duplicate = states
+            .iter()
+            .enumerate()
+            .find(|(n, state)| states[..*n].contains(*state));
+        if let Some((_, duplicate)) = duplicate {
+            return Err(StateSetError::DuplicateState(duplicate.as_ref().to_owned()));
+        }
+
+        Ok(states
+            .iter()
+            .map(|state| {
+                PrometheusInstance::new()
+                    .with_label(name, state.as_ref())
+                    .with_value(u8::from(state == current))
+            })
+            .collect())
+    }
+}
+
 impl<'a, N> Default for PrometheusInstance<'a, N, MissingValue>
 where
     N: Num + std::fmt::Display + std::fmt::Debug,
@@ -55,14 +162,45 @@ where
         labels.push((l.into(), v.into()));
 
         PrometheusInstance {
+            suffix: self.suffix,
             labels,
             value: self.value,
             timestamp: self.timestamp,
+            exemplar: self.exemplar,
             value_set: PhantomData {},
         }
     }
 
-    /// Adds the optional timestamp to the instance.
+    /// Appends `suffix` to the metric name, for example `_bucket`,
+    /// `_sum` and `_count` for the series of a histogram.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use prometheus_exporter_base::prelude::*;
+    ///
+    /// let rendered = PrometheusMetric::build()
+    ///     .with_name("request_duration_seconds")
+    ///     .with_metric_type(MetricType::Histogram)
+    ///     .with_help("Duration of the requests")
+    ///     .build()
+    ///     .render_and_append_instance(
+    ///         &PrometheusInstance::new()
+    ///             .with_suffix("_bucket")
+    ///             .with_label("le", "+Inf")
+    ///             .with_value(3),
+    ///     )
+    ///     .render();
+    ///
+    /// assert!(rendered.ends_with("request_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+    /// ```
+    pub fn with_suffix(self, suffix: &'a str) -> Self {
+        PrometheusInstance { suffix, ..self }
+    }
+
+    /// Adds the optional timestamp to the instance, in milliseconds
+    /// since the `UNIX_EPOCH`. The OpenMetrics format renders it in
+    /// seconds.
     ///
     /// Example:
     ///
@@ -75,9 +213,11 @@ where
     /// ```
     pub fn with_timestamp(self, timestamp: u128) -> Self {
         PrometheusInstance {
+            suffix: self.suffix,
             labels: self.labels,
             value: self.value,
             timestamp: Some(timestamp),
+            exemplar: self.exemplar,
             value_set: PhantomData {},
         }
     }
@@ -97,13 +237,55 @@ where
     /// ```
     pub fn with_current_timestamp(self) -> Result<Self, SystemTimeError> {
         Ok(PrometheusInstance {
+            suffix: self.suffix,
             labels: self.labels,
             value: self.value,
             timestamp: Some(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis()),
+            exemplar: self.exemplar,
             value_set: PhantomData {},
         })
     }
 
+    /// Attaches an exemplar to the sample, for the counters and the
+    /// histogram buckets. It's only rendered in the OpenMetrics format,
+    /// see [`PrometheusMetricBuilder::with_format`], and only for the
+    /// samples of counters and the `_bucket` samples of histograms; the
+    /// other samples drop it. Fails if the labels of the exemplar are
+    /// longer than 128 characters all together.
+    ///
+    /// [`PrometheusMetricBuilder::with_format`]: prometheus_metric_builder/struct.PrometheusMetricBuilder.html#method.with_format
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use prometheus_exporter_base::prelude::*;
+    ///
+    /// let rendered = PrometheusMetric::build()
+    ///     .with_name("requests")
+    ///     .with_metric_type(MetricType::Counter)
+    ///     .with_help("Number of requests")
+    ///     .with_format(ExpositionFormat::OpenMetrics)
+    ///     .build()
+    ///     .render_and_append_instance(
+    ///         &PrometheusInstance::new()
+    ///             .with_suffix("_total")
+    ///             .with_value(42)
+    ///             .with_exemplar(Exemplar::new(1.0).with_label("trace_id", "KOO5S4vxi0o"))
+    ///             .expect("exemplar labels too long"),
+    ///     )
+    ///     .render();
+    ///
+    /// assert!(rendered.ends_with("requests_total 42 # {trace_id=\"KOO5S4vxi0o\"} 1.0\n"));
+    /// ```
+    pub fn with_exemplar(self, exemplar: Exemplar<'a>) -> Result<Self, ExemplarTooLong> {
+        exemplar.validate()?;
+
+        Ok(PrometheusInstance {
+            exemplar: Some(exemplar),
+            ..self
+        })
+    }
+
     /// Adds the current value to the instance. The value
     /// will be formatted as float as per
     /// specification.
@@ -118,20 +300,24 @@ where
     /// ```
     pub fn with_value(self, value: N) -> PrometheusInstance<'a, N, Yes> {
         PrometheusInstance {
+            suffix: self.suffix,
             labels: self.labels,
             value: Some(value),
             timestamp: self.timestamp,
+            exemplar: self.exemplar,
             value_set: PhantomData {},
         }
     }
 }
 
-impl<'a, N> RenderToPrometheus for PrometheusInstance<'a, N, Yes>
+impl<'a, N> PrometheusInstance<'a, N, Yes>
 where
     N: Num + std::fmt::Display + std::fmt::Debug,
 {
-    fn render(&self) -> String {
-        let mut s = String::new();
+    /// Renders the sample without the exemplar. The timestamp is in
+    /// milliseconds in the text format, in seconds in OpenMetrics.
+    fn render_sample(&self, format: ExpositionFormat) -> String {
+        let mut s = self.suffix.to_owned();
 
         if self.labels.is_empty() {
             s.push_str(&format!(" {}", self.value.as_ref().unwrap()));
@@ -152,9 +338,37 @@ where
         }
         if let Some(timestamp) = self.timestamp {
             s.push(' ');
-            s.push_str(&timestamp.to_string());
+            match format {
+                ExpositionFormat::Text => s.push_str(&timestamp.to_string()),
+                ExpositionFormat::OpenMetrics => {
+                    s.push_str(&format!("{}.{:03}", timestamp / 1000, timestamp % 1000))
+                }
+            }
         }
 
         s
     }
 }
+
+impl<'a, N> RenderToPrometheus for PrometheusInstance<'a, N, Yes>
+where
+    N: Num + std::fmt::Display + std::fmt::Debug,
+{
+    /// Renders the sample without the exemplar, which the classic text
+    /// format does not support.
+    fn render(&self) -> String {
+        self.render_sample(ExpositionFormat::Text)
+    }
+
+    fn render_with_format(&self, format: ExpositionFormat) -> String {
+        self.render_sample(format)
+    }
+
+    fn suffix(&self) -> &str {
+        self.suffix
+    }
+
+    fn render_exemplar(&self) -> Option<String> {
+        self.exemplar.as_ref().map(Exemplar::render)
+    }
+}
@@ -1,11 +1,12 @@
 use crate::prometheus_metric_builder::PrometheusMetricBuilder;
-use crate::{MetricType, No, RenderToPrometheus};
+use crate::{ExpositionFormat, MetricType, No, RenderToPrometheus};
 
 #[derive(Debug)]
 pub struct PrometheusMetric<'a> {
     pub(crate) counter_name: &'a str,
     pub(crate) counter_type: MetricType,
     pub(crate) counter_help: &'a str,
+    pub(crate) format: ExpositionFormat,
     pub(crate) rendered_instances: Vec<String>,
 }
 
@@ -20,6 +21,7 @@ impl<'a> PrometheusMetric<'a> {
             counter_name,
             counter_type,
             counter_help,
+            format: ExpositionFormat::Text,
             rendered_instances: Vec::new(),
         }
     }
@@ -43,9 +45,22 @@ impl<'a> PrometheusMetric<'a> {
     }
 
     fn render_header(&self) -> String {
+        let (name, metric_type) = match (self.format, &self.counter_type) {
+            (ExpositionFormat::OpenMetrics, metric_type) => {
+                (self.counter_name.to_owned(), metric_type.as_ref())
+            }
+            // the classic format names the metric after its only sample
+            (ExpositionFormat::Text, MetricType::Info) => {
+                (format!("{}_info", self.counter_name), "gauge")
+            }
+            (ExpositionFormat::Text, metric_type) => {
+                (self.counter_name.to_owned(), metric_type.classic_name())
+            }
+        };
+
         format!(
             "# HELP {} {}\n# TYPE {} {}\n",
-            self.counter_name, self.counter_help, self.counter_name, self.counter_type
+            name, self.counter_help, name, metric_type
         )
     }
 
@@ -89,7 +104,18 @@ impl<'a> PrometheusMetric<'a> {
         &mut self,
         rendereable_instance: &dyn RenderToPrometheus,
     ) -> &mut Self {
-        self.rendered_instances.push(rendereable_instance.render());
+        let mut rendered = rendereable_instance.render_with_format(self.format);
+        if self.format == ExpositionFormat::OpenMetrics
+            && self
+                .counter_type
+                .allows_exemplars(rendereable_instance.suffix())
+        {
+            if let Some(exemplar) = rendereable_instance.render_exemplar() {
+                rendered.push_str(&exemplar);
+            }
+        }
+
+        self.rendered_instances.push(rendered);
         self
     }
 
@@ -108,7 +134,7 @@ impl<'a> PrometheusMetric<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{MetricType, PrometheusInstance};
+    use crate::{Exemplar, MetricType, PrometheusInstance, StateSetError};
 
     #[test]
     fn test_header() {
@@ -188,4 +214,154 @@ mod tests {
         gigino_total 100 9223372036854775807\n"
         );
     }
+
+    #[test]
+    fn test_openmetrics_types() {
+        let header = |metric_type, format| {
+            PrometheusMetric::build()
+                .with_name("pippo")
+                .with_metric_type(metric_type)
+                .with_help("Pippo")
+                .with_format(format)
+                .build()
+                .render_header()
+        };
+
+        assert_eq!(
+            header(MetricType::Info, ExpositionFormat::OpenMetrics),
+            "# HELP pippo Pippo\n# TYPE pippo info\n"
+        );
+        assert_eq!(
+            header(MetricType::Info, ExpositionFormat::Text),
+            "# HELP pippo_info Pippo\n# TYPE pippo_info gauge\n"
+        );
+        assert_eq!(
+            header(MetricType::StateSet, ExpositionFormat::Text),
+            "# HELP pippo Pippo\n# TYPE pippo gauge\n"
+        );
+        assert_eq!(
+            header(MetricType::Unknown, ExpositionFormat::OpenMetrics),
+            "# HELP pippo Pippo\n# TYPE pippo unknown\n"
+        );
+        assert_eq!(
+            header(MetricType::Unknown, ExpositionFormat::Text),
+            "# HELP pippo Pippo\n# TYPE pippo untyped\n"
+        );
+
+        let states = ["on", "off"];
+        let rendered = PrometheusInstance::state_set("pippo", &states, &"off")
+            .unwrap()
+            .iter()
+            .map(RenderToPrometheus::render)
+            .collect::<Vec<_>>();
+        assert_eq!(rendered, vec!["{pippo=\"on\"} 0", "{pippo=\"off\"} 1"]);
+        assert_eq!(
+            PrometheusInstance::state_set("pippo", &states, &"broken").unwrap_err(),
+            StateSetError::UnknownState("broken".to_owned())
+        );
+        assert_eq!(
+            PrometheusInstance::state_set("pippo", &["on", "off", "on"], &"off").unwrap_err(),
+            StateSetError::DuplicateState("on".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_exemplars() {
+        let render = |format| {
+            PrometheusMetric::build()
+                .with_name("latency_seconds")
+                .with_metric_type(MetricType::Histogram)
+                .with_help("Latency")
+                .with_format(format)
+                .build()
+                .render_and_append_instance(
+                    &PrometheusInstance::new()
+                        .with_suffix("_bucket")
+                        .with_label("le", "0.5")
+                        .with_value(7)
+                        .with_exemplar(
+                            Exemplar::new(0.25)
+                                .with_label("trace_id", "KOO5S4vxi0o")
+                                .with_timestamp(1234.5),
+                        )
+                        .unwrap(),
+                )
+                .render()
+        };
+
+        assert!(render(ExpositionFormat::OpenMetrics).ends_with(
+            "latency_seconds_bucket{le=\"0.5\"} 7 # {trace_id=\"KOO5S4vxi0o\"} 0.25 1234.5\n"
+        ));
+        assert!(render(ExpositionFormat::Text).ends_with("latency_seconds_bucket{le=\"0.5\"} 7\n"));
+
+        // only the counter and bucket samples carry exemplars
+        let exemplar = Exemplar::new(0.25).with_label("trace_id", "KOO5S4vxi0o");
+        let rendered = PrometheusMetric::build()
+            .with_name("latency_seconds")
+            .with_metric_type(MetricType::Histogram)
+            .with_help("Latency")
+            .with_format(ExpositionFormat::OpenMetrics)
+            .build()
+            .render_and_append_instance(
+                &PrometheusInstance::new()
+                    .with_suffix("_sum")
+                    .with_value(3)
+                    .with_exemplar(exemplar.clone())
+                    .unwrap(),
+            )
+            .render_and_append_instance(
+                &PrometheusInstance::new()
+                    .with_suffix("_count")
+                    .with_value(7)
+                    .with_exemplar(exemplar)
+                    .unwrap(),
+            )
+            .render();
+        assert!(rendered.ends_with("latency_seconds_sum 3\nlatency_seconds_count 7\n"));
+
+        // the metric type decides, not the suffix
+        let render = |metric_type, suffix| {
+            PrometheusMetric::build()
+                .with_name("requests")
+                .with_metric_type(metric_type)
+                .with_help("Requests")
+                .with_format(ExpositionFormat::OpenMetrics)
+                .build()
+                .render_and_append_instance(
+                    &PrometheusInstance::new()
+                        .with_suffix(suffix)
+                        .with_value(5)
+                        .with_exemplar(Exemplar::new(1.0))
+                        .unwrap(),
+                )
+                .render()
+        };
+        assert!(render(MetricType::Gauge, "_total").ends_with("requests_total 5\n"));
+        assert!(render(MetricType::Summary, "_total").ends_with("requests_total 5\n"));
+        assert!(render(MetricType::Counter, "").ends_with("requests 5 # {} 1.0\n"));
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let render = |format| {
+            PrometheusMetric::build()
+                .with_name("requests")
+                .with_metric_type(MetricType::Counter)
+                .with_help("Number of requests")
+                .with_format(format)
+                .build()
+                .render_and_append_instance(
+                    &PrometheusInstance::new()
+                        .with_suffix("_total")
+                        .with_value(42)
+                        .with_timestamp(1_520_879_607_089),
+                )
+                .render()
+        };
+
+        assert!(render(ExpositionFormat::Text).ends_with("requests_total 42 1520879607089\n"));
+        assert!(
+            render(ExpositionFormat::OpenMetrics).ends_with("requests_total 42 1520879607.089\n")
+        );
+    }
 }
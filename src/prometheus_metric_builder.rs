@@ -1,4 +1,4 @@
-use crate::{MetricType, No, PrometheusMetric, ToAssign, Yes};
+use crate::{ExpositionFormat, MetricType, No, PrometheusMetric, ToAssign, Yes};
 use std::marker::PhantomData;
 
 #[derive(Debug, Clone)]
@@ -14,6 +14,7 @@ where
     name: Option<&'a str>,
     metric_type: MetricType,
     help: Option<&'a str>,
+    format: ExpositionFormat,
 }
 
 impl<'a> PrometheusMetricBuilder<'a, No, No, No> {
@@ -26,6 +27,7 @@ impl<'a> PrometheusMetricBuilder<'a, No, No, No> {
             metric_type: MetricType::Gauge,
             p_help: PhantomData {},
             help: None,
+            format: ExpositionFormat::Text,
         }
     }
 }
@@ -83,6 +85,7 @@ where
             name: Some(name),
             metric_type: self.metric_type,
             help: self.help,
+            format: self.format,
         }
     }
 }
@@ -105,6 +108,7 @@ where
             name: self.name,
             metric_type,
             help: self.help,
+            format: self.format,
         }
     }
 }
@@ -127,6 +131,7 @@ where
             name: self.name,
             metric_type: self.metric_type,
             help: Some(help),
+            format: self.format,
         }
     }
 }
@@ -139,6 +144,15 @@ where
     MetricTypeSet: ToAssign,
     HelpSet: ToAssign,
 {
+    /// Specifies the format the instances are rendered in, the classic
+    /// text format by default. Pass [`ScrapeContext::format`] to render
+    /// what the scrape asked for.
+    ///
+    /// [`ScrapeContext::format`]: ../struct.ScrapeContext.html#structfield.format
+    #[inline]
+    pub fn with_format(self, format: ExpositionFormat) -> Self {
+        Self { format, ..self }
+    }
 }
 
 // methods callable only when every mandatory field has been filled
@@ -164,6 +178,7 @@ impl<'a> PrometheusMetricBuilder<'a, Yes, Yes, Yes> {
             counter_name: self.name(),
             counter_type: self.metric_type,
             counter_help: self.help(),
+            format: self.format,
             rendered_instances: Vec::new(),
         }
     }
@@ -0,0 +1,196 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Above this many clients, the oldest buckets are dropped.
+const MAX_BUCKETS: usize = 1024;
+
+/// What the requests are counted by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitKey {
+    /// The client IP address, taken from `X-Forwarded-For` for the
+    /// trusted proxies of the [`AccessControl`].
+    ///
+    /// [`AccessControl`]: struct.AccessControl.html
+    ClientAddr,
+    /// The authenticated user, the client IP address for the requests
+    /// without one. The user of [`Authorization::Basic`] is always empty
+    /// so those requests are counted by client IP address too.
+    ///
+    /// [`Authorization::Basic`]: enum.Authorization.html#variant.Basic
+    User,
+}
+
+/// A token bucket per client: every request takes a token, the bucket
+/// holds up to `burst` of them and refills at `requests_per_second`.
+/// Requests finding the bucket empty get `429 Too Many Requests`.
+///
+/// # Examples
+///
+/// ```
+/// use prometheus_exporter_base::prelude::*;
+///
+/// // one scrape every 5 seconds, allowing 3 in a row
+/// let rate_limit = RateLimit::new(0.2, 3).with_key(RateLimitKey::User);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimit {
+    pub requests_per_second: f64,
+    pub burst: u32,
+    pub key: RateLimitKey,
+}
+
+impl RateLimit {
+    pub fn new(requests_per_second: f64, burst: u32) -> Self {
+        Self {
+            requests_per_second,
+            burst,
+            key: RateLimitKey::ClientAddr,
+        }
+    }
+
+    pub fn with_key(self, key: RateLimitKey) -> Self {
+        Self { key, ..self }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    by_client: HashMap<String, Bucket>,
+    /// The clients, oldest first.
+    clients: VecDeque<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    rate_limit: RateLimit,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate_limit: RateLimit) -> Self {
+        Self {
+            rate_limit,
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    pub(crate) fn key(&self) -> RateLimitKey {
+        self.rate_limit.key
+    }
+
+    /// Takes a token from the bucket of `client`. If it's empty, returns
+    /// how long until a token is available.
+    pub(crate) fn acquire(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        let burst = self.rate_limit.burst as f64;
+        let rate = self.rate_limit.requests_per_second;
+        let mut buckets = self.buckets.lock().expect("rate limiter lock poisoned");
+
+        let buckets = &mut *buckets;
+
+        if !buckets.by_client.contains_key(client) {
+            if buckets.clients.len() >= MAX_BUCKETS {
+                if let Some(oldest) = buckets.clients.pop_front() {
+                    buckets.by_client.remove(&oldest);
+                }
+            }
+            buckets.clients.push_back(client.to_owned());
+        }
+
+        let bucket = buckets
+            .by_client
+            .entry(client.to_owned())
+            .or_insert(Bucket {
+                tokens: burst,
+                refilled_at: now,
+            });
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(burst);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if rate > 0.0 {
+            Err(Duration::try_from_secs_f64((1.0 - bucket.tokens) / rate).unwrap_or(Duration::MAX))
+        } else {
+            Err(Duration::MAX)
+        }
+    }
+}
+
+/// The value of the `Retry-After` header, in whole seconds.
+pub(crate) fn retry_after_seconds(retry_after: Duration) -> u64 {
+    let seconds = retry_after.as_secs();
+    if retry_after.subsec_nanos() > 0 || seconds == 0 {
+        seconds.saturating_add(1)
+    } else {
+        seconds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acquire() {
+        let limiter = RateLimiter::new(RateLimit::new(2.0, 3));
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.acquire("10.0.0.1", start), Ok(()));
+        }
+        assert_eq!(
+            limiter.acquire("10.0.0.1", start),
+            Err(Duration::from_millis(500))
+        );
+        // other clients have their own bucket
+        assert_eq!(limiter.acquire("10.0.0.2", start), Ok(()));
+
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.acquire("10.0.0.1", later), Ok(()));
+        assert!(limiter.acquire("10.0.0.1", later).is_err());
+
+        // the bucket never holds more than the burst
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(limiter.acquire("10.0.0.1", much_later), Ok(()));
+        }
+        assert!(limiter.acquire("10.0.0.1", much_later).is_err());
+    }
+
+    #[test]
+    fn test_max_buckets() {
+        let limiter = RateLimiter::new(RateLimit::new(1.0, 1));
+        let now = Instant::now();
+
+        assert_eq!(limiter.acquire("client 0", now), Ok(()));
+        assert!(limiter.acquire("client 0", now).is_err());
+        for client in 1..MAX_BUCKETS {
+            assert_eq!(limiter.acquire(&format!("client {}", client), now), Ok(()));
+        }
+        assert!(limiter.acquire("client 0", now).is_err());
+
+        // the oldest bucket makes room for the new client
+        assert_eq!(limiter.acquire("newcomer", now), Ok(()));
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.by_client.len(), MAX_BUCKETS);
+        assert_eq!(buckets.clients.len(), MAX_BUCKETS);
+        assert!(!buckets.by_client.contains_key("client 0"));
+    }
+
+    #[test]
+    fn test_retry_after_seconds() {
+        assert_eq!(retry_after_seconds(Duration::from_millis(1)), 1);
+        assert_eq!(retry_after_seconds(Duration::from_secs(2)), 2);
+        assert_eq!(retry_after_seconds(Duration::from_millis(2500)), 3);
+        assert_eq!(retry_after_seconds(Duration::MAX), u64::MAX);
+    }
+}
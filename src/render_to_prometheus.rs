@@ -1,3 +1,5 @@
+use crate::ExpositionFormat;
+
 /// This trait should be implemented by
 /// any instance able to render itself according
 /// to Prometheus specifications.
@@ -5,4 +7,21 @@ pub trait RenderToPrometheus: std::fmt::Debug {
     /// Render must return the instance formatted
     /// string without the metric info.
     fn render(&self) -> String;
+
+    /// Renders the instance in `format`. The default ignores the format,
+    /// which is fine for anything valid in both.
+    fn render_with_format(&self, _format: ExpositionFormat) -> String {
+        self.render()
+    }
+
+    /// The suffix appended to the metric name, for example `_bucket`.
+    fn suffix(&self) -> &str {
+        ""
+    }
+
+    /// The exemplar of the sample in the OpenMetrics syntax, leading
+    /// ` # ` included. The metric decides whether it's rendered.
+    fn render_exemplar(&self) -> Option<String> {
+        None
+    }
 }
@@ -0,0 +1,272 @@
+use crate::{Collection, MetricType, PrometheusInstance, PrometheusMetric};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The most entries kept, the oldest one is dropped to make room.
+const MAX_ENTRIES: usize = 256;
+
+#[derive(Debug)]
+struct CacheEntry {
+    collection: Arc<Collection>,
+    collected_at: Instant,
+    refreshing: bool,
+    etag: Option<String>,
+}
+
+/// The result of a cache lookup.
+#[derive(Debug, Clone)]
+pub(crate) enum CacheLookup {
+    Miss,
+    /// A collection is available. If `refresh` is
+    /// `true` the entry is stale and the caller is in charge
+    /// of refreshing it.
+    Hit {
+        collection: Arc<Collection>,
+        age: Duration,
+        refresh: bool,
+        etag: Option<String>,
+    },
+}
+
+/// Caches the output of the collector per scrape key. Stale entries
+/// are still served while a single refresh runs in the background.
+/// Storing an entry drops the expired ones and, past [`MAX_ENTRIES`],
+/// the oldest one.
+#[derive(Debug)]
+pub(crate) struct ScrapeCache {
+    ttl: Duration,
+    etag: bool,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl ScrapeCache {
+    /// If `etag` is `true` every entry gets an entity tag.
+    pub(crate) fn new(ttl: Duration, etag: bool) -> Self {
+        Self {
+            ttl,
+            etag,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn lookup(&self, key: &str) -> CacheLookup {
+        let mut entries = self.entries.lock().expect("scrape cache lock poisoned");
+
+        match entries.get_mut(key) {
+            Some(entry) => {
+                let age = entry.collected_at.elapsed();
+                // only the first scrape finding the entry stale refreshes it
+                let refresh = age >= self.ttl && !entry.refreshing;
+                if refresh {
+                    entry.refreshing = true;
+                }
+
+                CacheLookup::Hit {
+                    collection: entry.collection.clone(),
+                    age,
+                    refresh,
+                    etag: entry.etag.clone(),
+                }
+            }
+            None => CacheLookup::Miss,
+        }
+    }
+
+    /// The entity tag of the entry of `key`, if any. Unlike
+    /// [`lookup`](#method.lookup), it never starts a refresh.
+    pub(crate) fn etag(&self, key: &str) -> Option<String> {
+        self.entries
+            .lock()
+            .expect("scrape cache lock poisoned")
+            .get(key)
+            .and_then(|entry| entry.etag.clone())
+    }
+
+    pub(crate) fn store(&self, key: &str, collection: Arc<Collection>) {
+        // hash before locking, the collection can be large
+        let etag = if self.etag {
+            Some(entity_tag(&collection))
+        } else {
+            None
+        };
+
+        let mut entries = self.entries.lock().expect("scrape cache lock poisoned");
+        // the entries being refreshed are replaced soon anyway
+        entries.retain(|_, entry| entry.refreshing || entry.collected_at.elapsed() < self.ttl);
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.collected_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        entries.insert(
+            key.to_owned(),
+            CacheEntry {
+                collection,
+                collected_at: Instant::now(),
+                refreshing: false,
+                etag,
+            },
+        );
+    }
+
+    /// Called when a refresh did not produce a new entry so
+    /// the next scrape can try again.
+    pub(crate) fn refresh_failed(&self, key: &str) {
+        if let Some(entry) = self
+            .entries
+            .lock()
+            .expect("scrape cache lock poisoned")
+            .get_mut(key)
+        {
+            entry.refreshing = false;
+        }
+    }
+}
+
+/// A weak entity tag, shared by every encoding of the response. The
+/// tagged responses carry no trailer so the tag covers the whole body.
+fn entity_tag(collection: &Collection) -> String {
+    let mut hasher = DefaultHasher::new();
+    collection.metrics.hash(&mut hasher);
+    collection.errors.len().hash(&mut hasher);
+
+    format!("W/\"{:016x}\"", hasher.finish())
+}
+
+/// Whether `etag` is listed in the `If-None-Match` header,
+/// using the weak comparison.
+pub(crate) fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+
+    if_none_match.trim() == "*"
+        || if_none_match
+            .split(',')
+            .any(|tag| opaque(tag) == opaque(etag))
+}
+
+pub(crate) fn render_cache_age(age: Duration) -> String {
+    PrometheusMetric::build()
+        .with_name("exporter_cache_age_seconds")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("Age of the cached collection served by this scrape")
+        .build()
+        .render_and_append_instance(&PrometheusInstance::new().with_value(age.as_secs_f64()))
+        .render()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let cache = ScrapeCache::new(Duration::from_millis(50), false);
+        assert!(matches!(cache.lookup("/metrics"), CacheLookup::Miss));
+
+        let collection = Arc::new(Collection::from("pippo 1\n".to_owned()));
+        cache.store("/metrics", collection.clone());
+        match cache.lookup("/metrics") {
+            CacheLookup::Hit {
+                collection: cached,
+                refresh,
+                ..
+            } => {
+                assert!(Arc::ptr_eq(&cached, &collection));
+                assert!(!refresh);
+            }
+            CacheLookup::Miss => panic!("expected a cache hit"),
+        }
+        assert!(matches!(cache.lookup("/metrics?other"), CacheLookup::Miss));
+    }
+
+    #[test]
+    fn test_eviction() {
+        let cache = ScrapeCache::new(Duration::from_millis(50), false);
+        let cached =
+            |cache: &ScrapeCache, key: &str| matches!(cache.lookup(key), CacheLookup::Hit { .. });
+
+        cache.store("expired", Arc::new(Collection::new()));
+        std::thread::sleep(Duration::from_millis(60));
+        cache.store("fresh", Arc::new(Collection::new()));
+        assert!(!cached(&cache, "expired"));
+        assert!(cached(&cache, "fresh"));
+
+        for n in 0..MAX_ENTRIES + 10 {
+            cache.store(&n.to_string(), Arc::new(Collection::new()));
+        }
+        assert_eq!(cache.entries.lock().unwrap().len(), MAX_ENTRIES);
+        assert!(!cached(&cache, "fresh"));
+        assert!(cached(&cache, &(MAX_ENTRIES + 9).to_string()));
+    }
+
+    #[test]
+    fn test_stale_refreshed_once() {
+        let cache = ScrapeCache::new(Duration::from_millis(0), false);
+        cache.store("/metrics", Arc::new(Collection::new()));
+
+        let refreshes = |cache: &ScrapeCache| match cache.lookup("/metrics") {
+            CacheLookup::Hit { refresh, .. } => refresh,
+            CacheLookup::Miss => panic!("expected a cache hit"),
+        };
+
+        assert!(refreshes(&cache));
+        assert!(!refreshes(&cache));
+
+        cache.refresh_failed("/metrics");
+        assert!(refreshes(&cache));
+    }
+
+    #[test]
+    fn test_etag() {
+        let cache = ScrapeCache::new(Duration::from_secs(60), true);
+        let etag = |cache: &ScrapeCache| match cache.lookup("/metrics") {
+            CacheLookup::Hit { etag, .. } => etag.unwrap(),
+            CacheLookup::Miss => panic!("expected a cache hit"),
+        };
+
+        cache.store(
+            "/metrics",
+            Arc::new(Collection::from("pippo 1\n".to_owned())),
+        );
+        let first = etag(&cache);
+        assert!(first.starts_with("W/\""));
+
+        cache.store(
+            "/metrics",
+            Arc::new(Collection::from("pippo 1\n".to_owned())),
+        );
+        assert_eq!(etag(&cache), first);
+
+        cache.store(
+            "/metrics",
+            Arc::new(Collection::from("pippo 2\n".to_owned())),
+        );
+        assert_ne!(etag(&cache), first);
+
+        assert!(etag_matches(&first, &first));
+        assert!(etag_matches(
+            &format!("\"other\", {}", first.trim_start_matches("W/")),
+            &first
+        ));
+        assert!(etag_matches("*", &first));
+        assert!(!etag_matches("\"other\"", &first));
+    }
+
+    #[test]
+    fn test_render_cache_age() {
+        assert_eq!(
+            render_cache_age(Duration::from_millis(1500)),
+            "# HELP exporter_cache_age_seconds Age of the cached collection served by this scrape\n\
+            # TYPE exporter_cache_age_seconds gauge\n\
+            exporter_cache_age_seconds 1.5\n"
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use crate::{ExpositionFormat, Probe};
+use hyper::{Body, Request};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The header Prometheus uses to tell how long it will wait for the scrape.
+pub(crate) const SCRAPE_TIMEOUT_HEADER: &str = "x-prometheus-scrape-timeout-seconds";
+
+/// Everything a collector needs to know about the scrape it's serving,
+/// already parsed so collectors do not have to depend on `hyper`.
+#[derive(Debug, Clone, Default)]
+pub struct ScrapeContext {
+    /// The path of the scrape, for example `/metrics`.
+    pub path: String,
+    /// The decoded query parameters, in the order they were passed.
+    pub query: Vec<(String, String)>,
+    /// The headers listed in [`ServerOptions::context_headers`], names in lowercase.
+    ///
+    /// [`ServerOptions::context_headers`]: struct.ServerOptions.html#structfield.context_headers
+    pub headers: Vec<(String, String)>,
+    /// The address of the client, if known.
+    pub peer: Option<SocketAddr>,
+    /// The basic auth user name, if the scrape was authenticated.
+    pub identity: Option<String>,
+    /// The format the response will be sent with.
+    pub format: ExpositionFormat,
+    /// When Prometheus will give up on the scrape, if it told us.
+    pub deadline: Option<Instant>,
+    /// The validated probe, for `/probe` scrapes.
+    pub probe: Option<Probe>,
+    /// The `scrape` span of the request, if a subscriber records it. Use it
+    /// to tie exemplars to the trace of the scrape.
+    #[cfg(feature = "tracing")]
+    pub span: Option<tracing::Span>,
+}
+
+impl ScrapeContext {
+    pub(crate) fn from_request(
+        req: &Request<Body>,
+        context_headers: &[String],
+        peer: Option<SocketAddr>,
+        identity: Option<String>,
+        format: ExpositionFormat,
+    ) -> Self {
+        let query = form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+            .into_owned()
+            .collect();
+
+        let headers = req
+            .headers()
+            .iter()
+            .filter(|(header_name, _)| {
+                context_headers
+                    .iter()
+                    .any(|selected| header_name.as_str().eq_ignore_ascii_case(selected))
+            })
+            .filter_map(|(header_name, header_value)| {
+                Some((
+                    header_name.as_str().to_owned(),
+                    header_value.to_str().ok()?.to_owned(),
+                ))
+            })
+            .collect();
+
+        let deadline = req
+            .headers()
+            .get(SCRAPE_TIMEOUT_HEADER)
+            .and_then(|timeout| timeout.to_str().ok()?.parse::<f64>().ok())
+            .filter(|timeout| timeout.is_finite() && *timeout > 0.0)
+            .map(|timeout| Instant::now() + Duration::from_secs_f64(timeout));
+
+        Self {
+            path: req.uri().path().to_owned(),
+            query,
+            headers,
+            peer,
+            identity,
+            format,
+            deadline,
+            probe: req.extensions().get::<Probe>().cloned(),
+            #[cfg(feature = "tracing")]
+            span: Some(tracing::Span::current()).filter(|span| !span.is_disabled()),
+        }
+    }
+
+    /// Returns the first value of the query parameter `name`.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns every value of the query parameter `name`.
+    pub fn query_params<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.query
+            .iter()
+            .filter(move |(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the value of the header `name`, if it was selected.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// How much time is left before the deadline.
+    pub fn time_left(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_request() {
+        let req = Request::get("/metrics?collect%5B%5D=cpu&collect[]=disk&debug")
+            .header("User-Agent", "Prometheus/2.40.0")
+            .header("Authorization", "Basic OnBpcHBv")
+            .header(SCRAPE_TIMEOUT_HEADER, "10")
+            .body(Body::empty())
+            .unwrap();
+
+        let context = ScrapeContext::from_request(
+            &req,
+            &["user-agent".to_owned()],
+            Some(([127, 0, 0, 1], 4242).into()),
+            Some(String::new()),
+            ExpositionFormat::Text,
+        );
+
+        assert_eq!(context.path, "/metrics");
+        assert_eq!(
+            context.query_params("collect[]").collect::<Vec<_>>(),
+            vec!["cpu", "disk"]
+        );
+        assert_eq!(context.query_param("debug"), Some(""));
+        assert_eq!(context.query_param("missing"), None);
+        assert_eq!(context.header("User-Agent"), Some("Prometheus/2.40.0"));
+        assert_eq!(context.header("authorization"), None);
+        assert!(context.time_left().unwrap() > Duration::from_secs(9));
+        assert_eq!(context.probe, None);
+    }
+
+    #[test]
+    fn test_invalid_deadline() {
+        let req = Request::get("/metrics")
+            .header(SCRAPE_TIMEOUT_HEADER, "soon")
+            .body(Body::empty())
+            .unwrap();
+
+        let context = ScrapeContext::from_request(&req, &[], None, None, ExpositionFormat::Text);
+        assert_eq!(context.deadline, None);
+        assert_eq!(context.time_left(), None);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_span() {
+        let req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let from_request =
+            || ScrapeContext::from_request(&req, &[], None, None, ExpositionFormat::Text);
+
+        // no subscriber, no span
+        assert_eq!(from_request().span, None);
+
+        tracing::subscriber::with_default(tracing_subscriber::registry(), || {
+            let scrape = tracing::info_span!("scrape");
+            let context = scrape.in_scope(from_request);
+            assert_eq!(context.span.and_then(|span| span.id()), scrape.id());
+        });
+    }
+}
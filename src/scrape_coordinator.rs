@@ -0,0 +1,226 @@
+use crate::server_options::ServerOptions;
+use crate::Collection;
+use http::StatusCode;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OnceCell, Semaphore};
+
+/// Why a collection failed.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct CollectionError {
+    pub(crate) status: StatusCode,
+    pub(crate) message: String,
+}
+
+/// The outcome of a collection. It's shared between every
+/// scrape coalesced into the same collection so it must be cheap
+/// to clone.
+pub(crate) type SharedOutcome = Result<Arc<Collection>, Arc<CollectionError>>;
+
+/// Returned when no collection slot became free within
+/// the configured queue timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct QueueTimeout {}
+
+type Flight = Arc<OnceCell<Result<SharedOutcome, QueueTimeout>>>;
+
+/// Limits the number of concurrent collections and merges
+/// simultaneous scrapes of the same URI into a single
+/// collector call.
+#[derive(Debug)]
+pub(crate) struct ScrapeCoordinator {
+    semaphore: Option<Arc<Semaphore>>,
+    queue_timeout: Duration,
+    coalesce: bool,
+    in_flight: Mutex<HashMap<String, Flight>>,
+}
+
+impl ScrapeCoordinator {
+    pub(crate) fn new(server_options: &ServerOptions) -> Self {
+        Self {
+            semaphore: server_options
+                .max_concurrent_scrapes
+                .map(|max| Arc::new(Semaphore::new(max))),
+            queue_timeout: server_options.scrape_queue_timeout,
+            coalesce: server_options.coalesce_scrapes,
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Runs `collect` unless a collection for the same `key` is already
+    /// in flight, in which case its outcome is awaited and returned instead.
+    pub(crate) async fn collect<C, Fut>(
+        &self,
+        key: &str,
+        collect: C,
+    ) -> Result<SharedOutcome, QueueTimeout>
+    where
+        C: FnOnce() -> Fut,
+        Fut: Future<Output = SharedOutcome>,
+    {
+        if !self.coalesce {
+            return self.run_limited(collect).await;
+        }
+
+        let flight = self
+            .in_flight
+            .lock()
+            .expect("in flight scrapes lock poisoned")
+            .entry(key.to_owned())
+            .or_default()
+            .clone();
+
+        let outcome = flight
+            .get_or_init(|| self.run_limited(collect))
+            .await
+            .clone();
+
+        // the flight is over: the next scrape must trigger a new collection
+        let mut in_flight = self
+            .in_flight
+            .lock()
+            .expect("in flight scrapes lock poisoned");
+        if let Some(current) = in_flight.get(key) {
+            if Arc::ptr_eq(current, &flight) {
+                in_flight.remove(key);
+            }
+        }
+
+        outcome
+    }
+
+    async fn run_limited<C, Fut>(&self, collect: C) -> Result<SharedOutcome, QueueTimeout>
+    where
+        C: FnOnce() -> Fut,
+        Fut: Future<Output = SharedOutcome>,
+    {
+        let permit = match &self.semaphore {
+            Some(semaphore) => Some(
+                tokio::time::timeout(self.queue_timeout, semaphore.clone().acquire_owned())
+                    .await
+                    .map_err(|_| QueueTimeout {})?
+                    .expect("scrape semaphore closed"),
+            ),
+            None => None,
+        };
+
+        let mut outcome = collect().await;
+        // a stream keeps the slot until it ends, shared collections
+        // are never streamed
+        if let Ok(collection) = &mut outcome {
+            if let Some(collection) = Arc::get_mut(collection) {
+                collection.hold_while_streaming(permit);
+            }
+        }
+
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Authorization;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn coordinator(server_options: ServerOptions) -> Arc<ScrapeCoordinator> {
+        Arc::new(ScrapeCoordinator::new(&server_options))
+    }
+
+    fn server_options() -> ServerOptions {
+        ServerOptions::new(([127, 0, 0, 1], 0).into(), Authorization::None)
+    }
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let coordinator = coordinator(server_options());
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let scrapes = (0..4).map(|_| {
+            let coordinator = coordinator.clone();
+            let calls = calls.clone();
+            tokio::spawn(async move {
+                coordinator
+                    .collect("/metrics", || async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        Ok(Arc::new(Collection::from("pippo 1\n".to_owned())))
+                    })
+                    .await
+            })
+        });
+
+        for scrape in scrapes.collect::<Vec<_>>() {
+            let outcome = scrape.await.unwrap().unwrap().unwrap();
+            assert_eq!(outcome.metrics, "pippo 1\n");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // the flight is over so a new collection is started
+        coordinator
+            .collect("/metrics", || async { Ok(Arc::new(Collection::new())) })
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(coordinator.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_queue_timeout() {
+        let coordinator = coordinator(
+            server_options()
+                .with_coalesce_scrapes(false)
+                .with_max_concurrent_scrapes(1)
+                .with_scrape_queue_timeout(Duration::from_millis(50)),
+        );
+
+        let slow = {
+            let coordinator = coordinator.clone();
+            tokio::spawn(async move {
+                coordinator
+                    .collect("/metrics", || async {
+                        tokio::time::sleep(Duration::from_millis(500)).await;
+                        Ok(Arc::new(Collection::new()))
+                    })
+                    .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let rejected = coordinator
+            .collect("/metrics", || async { Ok(Arc::new(Collection::new())) })
+            .await;
+        assert!(matches!(rejected, Err(QueueTimeout {})));
+        assert!(slow.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_streaming_holds_slot() {
+        let coordinator = coordinator(
+            server_options()
+                .with_coalesce_scrapes(false)
+                .with_max_concurrent_scrapes(1)
+                .with_scrape_queue_timeout(Duration::from_millis(50)),
+        );
+        let (collection, sender) = Collection::streaming(1);
+        let streaming = coordinator
+            .collect("/metrics", || async { Ok(Arc::new(collection)) })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let collect =
+            || coordinator.collect("/metrics", || async { Ok(Arc::new(Collection::new())) });
+        // the stream still runs
+        assert!(matches!(collect().await, Err(QueueTimeout {})));
+
+        drop(sender);
+        let body = Arc::try_unwrap(streaming).unwrap().into_body(String::new());
+        hyper::body::to_bytes(body).await.unwrap();
+        assert!(collect().await.is_ok());
+        // a collection without a stream releases the slot right away
+        assert!(collect().await.is_ok());
+    }
+}
@@ -0,0 +1,325 @@
+use crate::catch_panic::panics;
+use crate::{ExpositionFormat, MetricType, PrometheusInstance, PrometheusMetric};
+use http::StatusCode;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The default Prometheus buckets, in seconds.
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+const SIZE_BUCKETS: &[f64] = &[
+    100.0,
+    1_000.0,
+    10_000.0,
+    100_000.0,
+    1_000_000.0,
+    10_000_000.0,
+];
+
+/// Builds the counter `name`, which ends with `_total`, returning it with
+/// the suffix its samples take: the text format names the family after
+/// its samples while OpenMetrics drops the suffix from the family name.
+pub(crate) fn counter<'a>(
+    name: &'a str,
+    help: &'a str,
+    format: ExpositionFormat,
+) -> (PrometheusMetric<'a>, &'static str) {
+    let (name, suffix) = match (format, name.strip_suffix("_total")) {
+        (ExpositionFormat::OpenMetrics, Some(family)) => (family, "_total"),
+        _ => (name, ""),
+    };
+
+    let metric = PrometheusMetric::build()
+        .with_name(name)
+        .with_metric_type(MetricType::Counter)
+        .with_help(help)
+        .with_format(format)
+        .build();
+
+    (metric, suffix)
+}
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// The observations per bucket, the last one being `+Inf`.
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: vec![0; bounds.len() + 1],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, name: &str, help: &str, format: ExpositionFormat) -> String {
+        let mut metric = PrometheusMetric::build()
+            .with_name(name)
+            .with_metric_type(MetricType::Histogram)
+            .with_help(help)
+            .with_format(format)
+            .build();
+
+        let mut cumulative = 0;
+        for (bucket, observations) in self.buckets.iter().enumerate() {
+            cumulative += observations;
+            let le = self
+                .bounds
+                .get(bucket)
+                .map_or_else(|| "+Inf".to_owned(), |bound| bound.to_string());
+            metric.render_and_append_instance(
+                &PrometheusInstance::new()
+                    .with_suffix("_bucket")
+                    .with_label("le", le.as_str())
+                    .with_value(cumulative as f64),
+            );
+        }
+        metric.render_and_append_instance(
+            &PrometheusInstance::new()
+                .with_suffix("_sum")
+                .with_value(self.sum),
+        );
+        metric.render_and_append_instance(
+            &PrometheusInstance::new()
+                .with_suffix("_count")
+                .with_value(self.count as f64),
+        );
+
+        metric.render()
+    }
+}
+
+#[derive(Debug)]
+struct Recorded {
+    /// The number of requests per path and status code.
+    requests: BTreeMap<(String, u16), u64>,
+    durations: Histogram,
+    response_sizes: Histogram,
+    last_scrape: Option<SystemTime>,
+    rate_limited: u64,
+}
+
+/// Metrics about the server itself, appended to every `/metrics` scrape.
+#[derive(Debug)]
+pub(crate) struct SelfMetrics {
+    recorded: Mutex<Recorded>,
+}
+
+impl SelfMetrics {
+    pub(crate) fn new() -> Self {
+        Self {
+            recorded: Mutex::new(Recorded {
+                requests: BTreeMap::new(),
+                durations: Histogram::new(DURATION_BUCKETS),
+                response_sizes: Histogram::new(SIZE_BUCKETS),
+                last_scrape: None,
+                rate_limited: 0,
+            }),
+        }
+    }
+
+    /// Records a served request. `path` must have a bounded
+    /// cardinality as it becomes a label.
+    pub(crate) fn record(
+        &self,
+        path: &str,
+        status: StatusCode,
+        duration: Duration,
+        response_size: Option<u64>,
+    ) {
+        let mut recorded = self.recorded.lock().expect("self metrics lock poisoned");
+
+        *recorded
+            .requests
+            .entry((path.to_owned(), status.as_u16()))
+            .or_default() += 1;
+        recorded.durations.observe(duration.as_secs_f64());
+        if let Some(response_size) = response_size {
+            recorded.response_sizes.observe(response_size as f64);
+        }
+        if path == "/metrics" && status == StatusCode::OK {
+            recorded.last_scrape = Some(SystemTime::now());
+        }
+    }
+
+    /// Records a request refused by the rate limiter.
+    pub(crate) fn record_rate_limited(&self) {
+        self.recorded
+            .lock()
+            .expect("self metrics lock poisoned")
+            .rate_limited += 1;
+    }
+
+    pub(crate) fn render(&self, format: ExpositionFormat) -> String {
+        let recorded = self.recorded.lock().expect("self metrics lock poisoned");
+
+        let (mut requests, suffix) = counter(
+            "exporter_http_requests_total",
+            "Number of HTTP requests served by the exporter",
+            format,
+        );
+        for ((path, code), count) in &recorded.requests {
+            requests.render_and_append_instance(
+                &PrometheusInstance::new()
+                    .with_suffix(suffix)
+                    .with_label("code", code.to_string().as_str())
+                    .with_label("path", path.as_str())
+                    .with_value(*count),
+            );
+        }
+
+        let mut s = requests.render();
+        s.push_str(&recorded.durations.render(
+            "exporter_http_request_duration_seconds",
+            "Time spent serving the HTTP requests in seconds",
+            format,
+        ));
+        s.push_str(&recorded.response_sizes.render(
+            "exporter_http_response_size_bytes",
+            "Size of the HTTP responses in bytes",
+            format,
+        ));
+        for (name, help, value) in [
+            (
+                "exporter_rate_limited_requests_total",
+                "Number of requests refused by the rate limiter",
+                recorded.rate_limited,
+            ),
+            (
+                "exporter_panics_total",
+                "Number of panics caught while collecting or serving requests",
+                panics(),
+            ),
+        ] {
+            let (mut metric, suffix) = counter(name, help, format);
+            s.push_str(
+                &metric
+                    .render_and_append_instance(
+                        &PrometheusInstance::new()
+                            .with_suffix(suffix)
+                            .with_value(value),
+                    )
+                    .render(),
+            );
+        }
+        if let Some(last_scrape) = recorded.last_scrape {
+            s.push_str(
+                &PrometheusMetric::build()
+                    .with_name("exporter_last_scrape_timestamp_seconds")
+                    .with_metric_type(MetricType::Gauge)
+                    .with_help("UNIX time of the previous successful scrape of /metrics")
+                    .with_format(format)
+                    .build()
+                    .render_and_append_instance(
+                        &PrometheusInstance::new().with_value(
+                            last_scrape
+                                .duration_since(UNIX_EPOCH)
+                                .unwrap_or_default()
+                                .as_secs_f64(),
+                        ),
+                    )
+                    .render(),
+            );
+        }
+
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::new(&[0.1, 1.0]);
+        histogram.observe(0.05);
+        histogram.observe(0.5);
+        histogram.observe(0.5);
+        histogram.observe(3.0);
+
+        assert_eq!(
+            histogram.render("latency_seconds", "Latency", ExpositionFormat::Text),
+            "# HELP latency_seconds Latency\n\
+            # TYPE latency_seconds histogram\n\
+            latency_seconds_bucket{le=\"0.1\"} 1\n\
+            latency_seconds_bucket{le=\"1\"} 3\n\
+            latency_seconds_bucket{le=\"+Inf\"} 4\n\
+            latency_seconds_sum 4.05\n\
+            latency_seconds_count 4\n"
+        );
+    }
+
+    #[test]
+    fn test_record() {
+        let self_metrics = SelfMetrics::new();
+        assert!(!self_metrics
+            .render(ExpositionFormat::Text)
+            .contains("exporter_last_scrape_timestamp_seconds"));
+
+        self_metrics.record(
+            "/metrics",
+            StatusCode::OK,
+            Duration::from_millis(20),
+            Some(512),
+        );
+        self_metrics.record(
+            "/metrics",
+            StatusCode::OK,
+            Duration::from_millis(30),
+            Some(512),
+        );
+        self_metrics.record(
+            "other",
+            StatusCode::NOT_FOUND,
+            Duration::from_millis(1),
+            Some(0),
+        );
+
+        let rendered = self_metrics.render(ExpositionFormat::Text);
+        assert!(rendered.contains(
+            "# TYPE exporter_http_requests_total counter\n\
+            exporter_http_requests_total{code=\"200\",path=\"/metrics\"} 2\n"
+        ));
+        assert!(rendered.contains("exporter_http_requests_total{code=\"404\",path=\"other\"} 1\n"));
+        assert!(rendered.contains("exporter_http_request_duration_seconds_count 3\n"));
+        assert!(rendered.contains("exporter_http_response_size_bytes_bucket{le=\"1000\"} 3\n"));
+        assert!(rendered.contains("exporter_last_scrape_timestamp_seconds "));
+        assert!(rendered.contains("exporter_rate_limited_requests_total 0\n"));
+        assert!(rendered.contains("\nexporter_panics_total "));
+
+        self_metrics.record_rate_limited();
+        assert!(self_metrics
+            .render(ExpositionFormat::Text)
+            .contains("exporter_rate_limited_requests_total 1\n"));
+
+        let rendered = self_metrics.render(ExpositionFormat::OpenMetrics);
+        assert!(rendered.contains(
+            "# TYPE exporter_http_requests counter\n\
+            exporter_http_requests_total{code=\"200\",path=\"/metrics\"} 2\n"
+        ));
+        assert!(rendered.contains(
+            "# TYPE exporter_rate_limited_requests counter\n\
+            exporter_rate_limited_requests_total 1\n"
+        ));
+    }
+}
@@ -1,4 +1,12 @@
+use crate::scrape_context::SCRAPE_TIMEOUT_HEADER;
+#[cfg(feature = "tls")]
+use crate::TlsOptions;
+use crate::{AccessControl, AccessLogFormat, RateLimit};
+use std::fmt;
 use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub enum Authorization {
@@ -6,8 +14,615 @@ pub enum Authorization {
     Basic(String),
 }
 
+/// Where the server listens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    /// A Unix domain socket. A stale socket file left at `path` is
+    /// replaced, the socket of a running server and other files are
+    /// not. If set, `mode` is applied to the socket file before it
+    /// accepts connections, for example `0o660` to restrict the access
+    /// to a group.
+    #[cfg(unix)]
+    Unix {
+        path: PathBuf,
+        mode: Option<u32>,
+    },
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ListenAddr::Tcp(addr)
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "http://{}", addr),
+            #[cfg(unix)]
+            ListenAddr::Unix { path, .. } => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// The HTTP versions the server speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpVersions {
+    /// HTTP/1.1 only.
+    Http1,
+    /// HTTP/2 only: h2c with prior knowledge in plaintext, h2 over TLS.
+    Http2,
+    /// Both. In plaintext HTTP/2 is recognized by its connection
+    /// preface, over TLS it's negotiated with ALPN.
+    Http1AndHttp2,
+}
+
+/// When the collector gets called.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollectionMode {
+    /// The collector is called for every scrape.
+    OnScrape,
+    /// The collector is called every `interval` in a background task and
+    /// scrapes are answered with the latest successful snapshot. After
+    /// consecutive failures the interval is doubled each time, up to
+    /// `max_backoff`.
+    Polling {
+        interval: Duration,
+        max_backoff: Duration,
+    },
+}
+
+/// A target the `/probe` endpoint is allowed to probe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeTarget {
+    pub target: String,
+    pub labels: Vec<(String, String)>,
+}
+
+impl ProbeTarget {
+    pub fn new(target: &str) -> Self {
+        Self {
+            target: target.to_owned(),
+            labels: Vec::new(),
+        }
+    }
+
+    /// Adds a label describing the target.
+    pub fn with_label(self, l: &str, v: &str) -> Self {
+        let mut labels = self.labels;
+        labels.push((l.to_owned(), v.to_owned()));
+
+        Self { labels, ..self }
+    }
+}
+
+/// The allowlists of the `/probe` endpoint.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProbeOptions {
+    pub targets: Vec<ProbeTarget>,
+    /// The allowed modules. The first one is used when the
+    /// scrape does not specify any.
+    pub modules: Vec<String>,
+    /// If set, the targets are listed at this path in the
+    /// format expected by Prometheus `http_sd_configs`.
+    pub service_discovery_path: Option<String>,
+}
+
+impl ProbeOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_target(self, target: ProbeTarget) -> Self {
+        let mut targets = self.targets;
+        targets.push(target);
+
+        Self { targets, ..self }
+    }
+
+    pub fn with_module(self, module: &str) -> Self {
+        let mut modules = self.modules;
+        modules.push(module.to_owned());
+
+        Self { modules, ..self }
+    }
+
+    /// Serves the targets for Prometheus HTTP service discovery at `path`,
+    /// for example `/targets`.
+    pub fn with_service_discovery(self, path: &str) -> Self {
+        Self {
+            service_discovery_path: Some(path.to_owned()),
+            ..self
+        }
+    }
+}
+
+/// The options of the hyper server. Build them with [`ServerOptions::new`]
+/// and the `with_*` methods: new options can be added in minor
+/// releases, so the struct can't be built literally.
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct ServerOptions {
-    pub addr: SocketAddr,
+    /// The addresses to listen on, at least one. The server does not
+    /// start unless it can bind them all.
+    pub listeners: Vec<ListenAddr>,
     pub authorization: Authorization,
+    /// Maximum number of collections allowed to run at the same time,
+    /// streamed ones included until their stream ends. `None` means no
+    /// limit.
+    pub max_concurrent_scrapes: Option<usize>,
+    /// How long a scrape waits for a free collection slot before
+    /// being answered with `503 Service Unavailable`.
+    pub scrape_queue_timeout: Duration,
+    /// If `true`, scrapes asking for the same output arriving while a
+    /// collection is in flight wait for it and share its result instead
+    /// of calling the collector again. Scrapes ask for the same output
+    /// when they have the same path, format and `collect[]` parameters
+    /// and, for `/probe`, the same target and module; the other query
+    /// parameters are not taken into account.
+    pub coalesce_scrapes: bool,
+    /// If set, the rendered output of the collector is cached
+    /// for this long. Once expired, the stale copy is still served
+    /// while a refresh runs in the background. Entries are shared like
+    /// the coalesced scrapes and at most 256 of them are kept.
+    pub cache_ttl: Option<Duration>,
+    /// Whether the collector is called on scrape or polled in the
+    /// background. Concurrency limits, coalescing and caching only
+    /// apply to [`CollectionMode::OnScrape`].
+    pub collection_mode: CollectionMode,
+    /// If set, the server answers `/probe?target=...&module=...`
+    /// for the allowed targets and modules and, optionally, lists
+    /// them for HTTP service discovery.
+    pub probe: Option<ProbeOptions>,
+    /// The request headers copied in the [`ScrapeContext`], matched
+    /// case insensitively.
+    ///
+    /// [`ScrapeContext`]: struct.ScrapeContext.html
+    pub context_headers: Vec<String>,
+    /// If `true`, scrapes preferring OpenMetrics are answered with the
+    /// OpenMetrics content type and the collector is told so through
+    /// [`ScrapeContext::format`]. It's up to the collector to render
+    /// accordingly.
+    ///
+    /// [`ScrapeContext::format`]: struct.ScrapeContext.html#structfield.format
+    pub openmetrics: bool,
+    /// If set, a scrape whose [`Collection`] carries at least this many
+    /// errors fails with `500 Internal Server Error` instead of serving
+    /// the partial metrics.
+    ///
+    /// [`Collection`]: struct.Collection.html
+    pub scrape_error_threshold: Option<usize>,
+    /// If `true`, `/metrics` scrapes end with the `process_*` metrics
+    /// of the exporter itself. Only available on Linux.
+    pub process_metrics: bool,
+    /// If `true` (the default), `/metrics` scrapes end with metrics
+    /// about the server itself: `exporter_http_requests_total`,
+    /// `exporter_http_request_duration_seconds`,
+    /// `exporter_http_response_size_bytes` and
+    /// `exporter_last_scrape_timestamp_seconds`.
+    pub self_metrics: bool,
+    /// If `true` (the default), responses are compressed with the
+    /// best encoding accepted by the client: gzip, or deflate and zstd
+    /// if the matching cargo features are enabled.
+    pub compression: bool,
+    /// Responses smaller than this many bytes are never compressed.
+    /// Defaults to 1 KiB.
+    pub compression_min_size: usize,
+    /// If `true`, responses served from the scrape cache carry an
+    /// `ETag` and scrapes sending a matching `If-None-Match` get
+    /// `304 Not Modified`. Those responses leave out the cache age,
+    /// self and process metrics as they change on every scrape.
+    /// Requires [`cache_ttl`](#structfield.cache_ttl).
+    pub etag: bool,
+    /// If `true`, the server serves the listening sockets passed by
+    /// systemd socket activation (`LISTEN_FDS`, `LISTEN_PID` and
+    /// `LISTEN_FDNAMES`) instead of binding
+    /// [`listeners`](#structfield.listeners), which are only bound when
+    /// no socket is passed. Only available on Unix.
+    pub socket_activation: bool,
+    /// If set, only the allowed client addresses can reach the server,
+    /// the others get `403 Forbidden`.
+    pub access_control: Option<AccessControl>,
+    /// If set, the requests of each client are rate limited, the
+    /// excess ones get `429 Too Many Requests` with a `Retry-After`.
+    pub rate_limit: Option<RateLimit>,
+    /// How long a client has to send the request headers once it starts.
+    /// Defaults to 10 seconds.
+    pub header_read_timeout: Option<Duration>,
+    /// Connections neither serving a request nor exchanging any byte
+    /// for this long are closed, including the ones never sending a
+    /// request. Defaults to 2 minutes.
+    pub idle_timeout: Option<Duration>,
+    /// If `true` (the default), connections are kept open between
+    /// requests.
+    pub keep_alive: bool,
+    /// The maximum size of the request headers, at least 8 KiB.
+    /// Larger requests are refused. Defaults to 16 KiB.
+    pub max_header_size: usize,
+    /// Requests announcing a larger body get `413 Payload Too Large`.
+    /// Defaults to 64 KiB, scrapes have no body anyway.
+    pub max_request_body_size: Option<u64>,
+    /// Defaults to [`HttpVersions::Http1`].
+    pub http_versions: HttpVersions,
+    /// If set, the TCP listeners serve HTTPS with this certificate.
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsOptions>,
+    /// If set, every request is logged in this format, with the
+    /// client, the user, the status, the size and the latency.
+    pub access_log: Option<AccessLogFormat>,
+}
+
+impl ServerOptions {
+    /// Creates the options with the given address and authorization.
+    /// Every other option starts with its default value: no
+    /// concurrency limit, a 10 seconds queue timeout, scrape
+    /// coalescing enabled, no caching, collection on scrape, no
+    /// `/probe` endpoint, the `User-Agent` and Prometheus scrape timeout
+    /// headers in the [`ScrapeContext`] and the classic text format only.
+    ///
+    /// [`ScrapeContext`]: struct.ScrapeContext.html
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use prometheus_exporter_base::prelude::*;
+    /// use std::time::Duration;
+    ///
+    /// let server_options = ServerOptions::new(([0, 0, 0, 0], 32221).into(), Authorization::None)
+    ///     .with_max_concurrent_scrapes(2)
+    ///     .with_scrape_queue_timeout(Duration::from_secs(5));
+    /// ```
+    pub fn new(addr: SocketAddr, authorization: Authorization) -> Self {
+        Self {
+            listeners: vec![ListenAddr::Tcp(addr)],
+            authorization,
+            max_concurrent_scrapes: None,
+            scrape_queue_timeout: Duration::from_secs(10),
+            coalesce_scrapes: true,
+            cache_ttl: None,
+            collection_mode: CollectionMode::OnScrape,
+            probe: None,
+            context_headers: vec!["user-agent".to_owned(), SCRAPE_TIMEOUT_HEADER.to_owned()],
+            openmetrics: false,
+            scrape_error_threshold: None,
+            process_metrics: false,
+            self_metrics: true,
+            compression: true,
+            compression_min_size: 1024,
+            etag: false,
+            socket_activation: false,
+            access_control: None,
+            rate_limit: None,
+            header_read_timeout: Some(Duration::from_secs(10)),
+            idle_timeout: Some(Duration::from_secs(120)),
+            keep_alive: true,
+            max_header_size: 16 * 1024,
+            max_request_body_size: Some(64 * 1024),
+            http_versions: HttpVersions::Http1,
+            #[cfg(feature = "tls")]
+            tls: None,
+            access_log: None,
+        }
+    }
+
+    /// Listens on `listener` too.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use prometheus_exporter_base::prelude::*;
+    /// use std::net::{Ipv6Addr, SocketAddr};
+    ///
+    /// let server_options = ServerOptions::new(([0, 0, 0, 0], 32221).into(), Authorization::None)
+    ///     .with_listener(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 32221)))
+    ///     .with_listener(ListenAddr::Unix {
+    ///         path: "/run/exporter/metrics.sock".into(),
+    ///         mode: Some(0o660),
+    ///     });
+    /// ```
+    pub fn with_listener<L>(self, listener: L) -> Self
+    where
+        L: Into<ListenAddr>,
+    {
+        let mut listeners = self.listeners;
+        listeners.push(listener.into());
+
+        Self { listeners, ..self }
+    }
+
+    /// Limits the number of collections running at the same time.
+    pub fn with_max_concurrent_scrapes(self, max_concurrent_scrapes: usize) -> Self {
+        Self {
+            max_concurrent_scrapes: Some(max_concurrent_scrapes),
+            ..self
+        }
+    }
+
+    /// Sets how long a scrape can wait for a free collection slot.
+    pub fn with_scrape_queue_timeout(self, scrape_queue_timeout: Duration) -> Self {
+        Self {
+            scrape_queue_timeout,
+            ..self
+        }
+    }
+
+    /// Enables or disables the coalescing of simultaneous scrapes.
+    pub fn with_coalesce_scrapes(self, coalesce_scrapes: bool) -> Self {
+        Self {
+            coalesce_scrapes,
+            ..self
+        }
+    }
+
+    /// Caches the rendered output of the collector for `cache_ttl`.
+    pub fn with_cache_ttl(self, cache_ttl: Duration) -> Self {
+        Self {
+            cache_ttl: Some(cache_ttl),
+            ..self
+        }
+    }
+
+    /// Chooses between collecting on scrape and polling in the background.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use prometheus_exporter_base::prelude::*;
+    /// use std::time::Duration;
+    ///
+    /// let server_options = ServerOptions::new(([0, 0, 0, 0], 32221).into(), Authorization::None)
+    ///     .with_collection_mode(CollectionMode::Polling {
+    ///         interval: Duration::from_secs(30),
+    ///         max_backoff: Duration::from_secs(300),
+    ///     });
+    /// ```
+    pub fn with_collection_mode(self, collection_mode: CollectionMode) -> Self {
+        Self {
+            collection_mode,
+            ..self
+        }
+    }
+
+    /// Enables the `/probe` endpoint for multi-target exporters.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use prometheus_exporter_base::prelude::*;
+    ///
+    /// let server_options = ServerOptions::new(([0, 0, 0, 0], 32221).into(), Authorization::None)
+    ///     .with_probe(
+    ///         ProbeOptions::new()
+    ///             .with_target(ProbeTarget::new("10.0.0.1:161").with_label("site", "milan"))
+    ///             .with_module("if_mib")
+    ///             .with_service_discovery("/targets"),
+    ///     );
+    /// ```
+    pub fn with_probe(self, probe: ProbeOptions) -> Self {
+        Self {
+            probe: Some(probe),
+            ..self
+        }
+    }
+
+    /// Copies the request header `name` in the [`ScrapeContext`].
+    ///
+    /// [`ScrapeContext`]: struct.ScrapeContext.html
+    pub fn with_context_header(self, name: &str) -> Self {
+        let mut context_headers = self.context_headers;
+        context_headers.push(name.to_owned());
+
+        Self {
+            context_headers,
+            ..self
+        }
+    }
+
+    /// Enables or disables the OpenMetrics content negotiation.
+    pub fn with_openmetrics(self, openmetrics: bool) -> Self {
+        Self {
+            openmetrics,
+            ..self
+        }
+    }
+
+    /// Fails the scrapes carrying at least `scrape_error_threshold` errors.
+    pub fn with_scrape_error_threshold(self, scrape_error_threshold: usize) -> Self {
+        Self {
+            scrape_error_threshold: Some(scrape_error_threshold),
+            ..self
+        }
+    }
+
+    /// Appends the `process_*` metrics, read from `/proc/self`,
+    /// to every `/metrics` scrape.
+    pub fn with_process_metrics(self, process_metrics: bool) -> Self {
+        Self {
+            process_metrics,
+            ..self
+        }
+    }
+
+    /// Enables or disables the metrics about the server itself.
+    pub fn with_self_metrics(self, self_metrics: bool) -> Self {
+        Self {
+            self_metrics,
+            ..self
+        }
+    }
+
+    /// Enables or disables the response compression.
+    pub fn with_compression(self, compression: bool) -> Self {
+        Self {
+            compression,
+            ..self
+        }
+    }
+
+    /// Sets the size, in bytes, below which responses are not compressed.
+    pub fn with_compression_min_size(self, compression_min_size: usize) -> Self {
+        Self {
+            compression_min_size,
+            ..self
+        }
+    }
+
+    /// Enables or disables the `ETag` of the cached responses.
+    pub fn with_etag(self, etag: bool) -> Self {
+        Self { etag, ..self }
+    }
+
+    /// Enables or disables systemd socket activation.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use prometheus_exporter_base::prelude::*;
+    ///
+    /// // binds port 32221 unless started by a systemd .socket unit
+    /// let server_options = ServerOptions::new(([0, 0, 0, 0], 32221).into(), Authorization::None)
+    ///     .with_socket_activation(true);
+    /// ```
+    pub fn with_socket_activation(self, socket_activation: bool) -> Self {
+        Self {
+            socket_activation,
+            ..self
+        }
+    }
+
+    /// Restricts the client addresses allowed to reach the server.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use prometheus_exporter_base::prelude::*;
+    ///
+    /// // Prometheus scrapes through the reverse proxy at 10.0.0.2
+    /// let server_options = ServerOptions::new(([0, 0, 0, 0], 32221).into(), Authorization::None)
+    ///     .with_access_control(
+    ///         AccessControl::new()
+    ///             .with_allow("192.168.10.0/24".parse().unwrap())
+    ///             .with_trusted_proxy("10.0.0.2".parse().unwrap()),
+    ///     );
+    /// ```
+    pub fn with_access_control(self, access_control: AccessControl) -> Self {
+        Self {
+            access_control: Some(access_control),
+            ..self
+        }
+    }
+
+    /// Rate limits the requests of each client.
+    pub fn with_rate_limit(self, rate_limit: RateLimit) -> Self {
+        Self {
+            rate_limit: Some(rate_limit),
+            ..self
+        }
+    }
+
+    /// Sets the time allowed to send the request headers, `None` to
+    /// wait forever.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use prometheus_exporter_base::prelude::*;
+    /// use std::time::Duration;
+    ///
+    /// let server_options = ServerOptions::new(([0, 0, 0, 0], 32221).into(), Authorization::None)
+    ///     .with_header_read_timeout(Some(Duration::from_secs(5)))
+    ///     .with_idle_timeout(Some(Duration::from_secs(30)))
+    ///     .with_max_header_size(8 * 1024);
+    /// ```
+    pub fn with_header_read_timeout(self, header_read_timeout: Option<Duration>) -> Self {
+        Self {
+            header_read_timeout,
+            ..self
+        }
+    }
+
+    /// Sets how long idle connections are kept open, `None` to never
+    /// close them.
+    pub fn with_idle_timeout(self, idle_timeout: Option<Duration>) -> Self {
+        Self {
+            idle_timeout,
+            ..self
+        }
+    }
+
+    /// Enables or disables keep-alive connections.
+    pub fn with_keep_alive(self, keep_alive: bool) -> Self {
+        Self { keep_alive, ..self }
+    }
+
+    /// Sets the maximum size of the request headers, in bytes.
+    pub fn with_max_header_size(self, max_header_size: usize) -> Self {
+        Self {
+            max_header_size,
+            ..self
+        }
+    }
+
+    /// Sets the maximum size of the request body, in bytes, `None` for
+    /// no limit.
+    pub fn with_max_request_body_size(self, max_request_body_size: Option<u64>) -> Self {
+        Self {
+            max_request_body_size,
+            ..self
+        }
+    }
+
+    /// Sets the HTTP versions spoken by the server.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use prometheus_exporter_base::prelude::*;
+    ///
+    /// let server_options = ServerOptions::new(([0, 0, 0, 0], 32221).into(), Authorization::None)
+    ///     .with_http_versions(HttpVersions::Http1AndHttp2);
+    /// ```
+    pub fn with_http_versions(self, http_versions: HttpVersions) -> Self {
+        Self {
+            http_versions,
+            ..self
+        }
+    }
+
+    /// Serves HTTPS on the TCP listeners.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use prometheus_exporter_base::prelude::*;
+    ///
+    /// let server_options = ServerOptions::new(([0, 0, 0, 0], 32221).into(), Authorization::None)
+    ///     .with_tls(TlsOptions::new("/etc/exporter/cert.pem", "/etc/exporter/key.pem"))
+    ///     .with_http_versions(HttpVersions::Http1AndHttp2);
+    /// ```
+    #[cfg(feature = "tls")]
+    pub fn with_tls(self, tls: TlsOptions) -> Self {
+        Self {
+            tls: Some(tls),
+            ..self
+        }
+    }
+
+    /// Logs every request in `format`.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use prometheus_exporter_base::prelude::*;
+    ///
+    /// let server_options = ServerOptions::new(([0, 0, 0, 0], 32221).into(), Authorization::None)
+    ///     .with_access_log(AccessLogFormat::Json);
+    /// ```
+    pub fn with_access_log(self, format: AccessLogFormat) -> Self {
+        Self {
+            access_log: Some(format),
+            ..self
+        }
+    }
 }
@@ -0,0 +1,338 @@
+use crate::HttpVersions;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use log::debug;
+use std::error::Error;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// Clients not done with the handshake after this long are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most handshakes run at once, the next connections wait to be
+/// accepted.
+const MAX_HANDSHAKES: usize = 256;
+
+/// The certificate and the private key of the HTTPS server, both PEM
+/// encoded. The certificate file may hold the whole chain, leaf first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsOptions {
+    pub certificate_chain_path: PathBuf,
+    pub private_key_path: PathBuf,
+}
+
+impl TlsOptions {
+    pub fn new<C: Into<PathBuf>, K: Into<PathBuf>>(
+        certificate_chain_path: C,
+        private_key_path: K,
+    ) -> Self {
+        Self {
+            certificate_chain_path: certificate_chain_path.into(),
+            private_key_path: private_key_path.into(),
+        }
+    }
+}
+
+/// Loads the certificate and the key, advertising the HTTP versions
+/// of the server through ALPN.
+pub(crate) fn acceptor(
+    tls_options: &TlsOptions,
+    http_versions: HttpVersions,
+) -> Result<TlsAcceptor, Box<dyn Error + Send + Sync>> {
+    let read_pem = |path: &Path| -> Result<_, Box<dyn Error + Send + Sync>> {
+        let file =
+            File::open(path).map_err(|err| format!("cannot open {}: {}", path.display(), err))?;
+        Ok(rustls_pemfile::read_all(&mut BufReader::new(file))?)
+    };
+
+    let certificates = read_pem(&tls_options.certificate_chain_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if certificates.is_empty() {
+        return Err(format!(
+            "no certificate found in {}",
+            tls_options.certificate_chain_path.display()
+        )
+        .into());
+    }
+    let private_key = read_pem(&tls_options.private_key_path)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            format!(
+                "no private key found in {}",
+                tls_options.private_key_path.display()
+            )
+        })?;
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certificates, private_key)?;
+    config.alpn_protocols = match http_versions {
+        HttpVersions::Http1 => vec![b"http/1.1".to_vec()],
+        HttpVersions::Http2 => vec![b"h2".to_vec()],
+        HttpVersions::Http1AndHttp2 => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+    };
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+type Handshake = Pin<Box<dyn Future<Output = io::Result<TlsStream<AddrStream>>> + Send>>;
+
+/// Accepts TCP connections and hands them out once the TLS handshake
+/// is done. Handshakes run concurrently so a slow client does not hold
+/// up the others, up to `max_handshakes` of them.
+pub(crate) struct TlsIncoming {
+    incoming: AddrIncoming,
+    acceptor: TlsAcceptor,
+    handshakes: FuturesUnordered<Handshake>,
+    max_handshakes: usize,
+}
+
+impl TlsIncoming {
+    pub(crate) fn new(incoming: AddrIncoming, acceptor: TlsAcceptor) -> Self {
+        Self {
+            incoming,
+            acceptor,
+            handshakes: FuturesUnordered::new(),
+            max_handshakes: MAX_HANDSHAKES,
+        }
+    }
+}
+
+impl Accept for TlsIncoming {
+    type Conn = TlsStream<AddrStream>;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        loop {
+            // the finished handshakes free their slot
+            while let Poll::Ready(Some(handshake)) = self.handshakes.poll_next_unpin(cx) {
+                match handshake {
+                    Ok(stream) => return Poll::Ready(Some(Ok(stream))),
+                    Err(err) => debug!("TLS handshake failed == {:?}", err),
+                }
+            }
+
+            // the connections past the limit wait in the listen backlog,
+            // a finished handshake wakes us up
+            if self.handshakes.len() >= self.max_handshakes {
+                return Poll::Pending;
+            }
+
+            match Pin::new(&mut self.incoming).poll_accept(cx) {
+                Poll::Ready(Some(Ok(stream))) => {
+                    let handshake = self.acceptor.accept(stream);
+                    self.handshakes.push(Box::pin(async move {
+                        tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake)
+                            .await
+                            .unwrap_or_else(|_| {
+                                Err(io::Error::new(
+                                    io::ErrorKind::TimedOut,
+                                    "TLS handshake timed out",
+                                ))
+                            })
+                    }));
+                }
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(None) if self.handshakes.is_empty() => return Poll::Ready(None),
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{render_prometheus, Authorization, ServerOptions};
+    use hyper::{Body, Request, StatusCode};
+    use std::convert::TryFrom;
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerName};
+    use tokio_rustls::TlsConnector;
+
+    /// Writes a self signed certificate for `localhost` and its key,
+    /// their file names starting with `prefix`.
+    fn write_certificate(prefix: &str) -> (Certificate, TlsOptions) {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let dir = std::env::temp_dir();
+        let certificate_path = dir.join(format!("{}-{}-cert.pem", prefix, std::process::id()));
+        let private_key_path = dir.join(format!("{}-{}-key.pem", prefix, std::process::id()));
+        std::fs::write(&certificate_path, generated.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&private_key_path, generated.serialize_private_key_pem()).unwrap();
+
+        (
+            Certificate(generated.serialize_der().unwrap()),
+            TlsOptions::new(certificate_path, private_key_path),
+        )
+    }
+
+    fn remove_certificate(tls_options: &TlsOptions) {
+        std::fs::remove_file(&tls_options.certificate_chain_path).unwrap();
+        std::fs::remove_file(&tls_options.private_key_path).unwrap();
+    }
+
+    async fn connect(
+        addr: std::net::SocketAddr,
+        certificate: &Certificate,
+        alpn: &[&[u8]],
+    ) -> io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(certificate).unwrap();
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+
+        let tcp = TcpStream::connect(addr).await?;
+        TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await
+    }
+
+    /// Scrapes `/metrics` over TLS offering `alpn`, returning the
+    /// negotiated protocol and the response body.
+    async fn scrape(
+        addr: std::net::SocketAddr,
+        certificate: &Certificate,
+        alpn: &[&[u8]],
+    ) -> (Option<Vec<u8>>, String) {
+        let tls = connect(addr, certificate, alpn).await.unwrap();
+        let negotiated = tls.get_ref().1.alpn_protocol().map(<[u8]>::to_vec);
+
+        let (mut sender, connection) = hyper::client::conn::Builder::new()
+            .http2_only(negotiated.as_deref() == Some(b"h2"))
+            .handshake(tls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        let response = sender
+            .send_request(
+                Request::get("https://localhost/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (negotiated, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (certificate, tls_options) = write_certificate("exporter-handshake");
+
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server_options = ServerOptions::new(addr, Authorization::None)
+            .with_tls(tls_options.clone())
+            .with_http_versions(HttpVersions::Http1AndHttp2)
+            .with_self_metrics(false);
+        let server = tokio::spawn(render_prometheus(server_options, (), |_, _| async {
+            Ok::<_, Box<dyn Error + Send + Sync>>("pippo 1\n".to_owned())
+        }));
+        while TcpStream::connect(addr).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let (negotiated, body) = scrape(addr, &certificate, &[b"h2", b"http/1.1"]).await;
+        assert_eq!(negotiated.as_deref(), Some(&b"h2"[..]));
+        assert!(body.starts_with("pippo 1\n"));
+
+        let (negotiated, body) = scrape(addr, &certificate, &[b"http/1.1"]).await;
+        assert_eq!(negotiated.as_deref(), Some(&b"http/1.1"[..]));
+        assert!(body.starts_with("pippo 1\n"));
+
+        // clients without ALPN fall back to HTTP/1.1
+        let (negotiated, body) = scrape(addr, &certificate, &[]).await;
+        assert_eq!(negotiated, None);
+        assert!(body.starts_with("pippo 1\n"));
+
+        server.abort();
+        remove_certificate(&tls_options);
+    }
+
+    async fn accept(
+        incoming: &mut TlsIncoming,
+        wait: Duration,
+    ) -> Result<Option<io::Result<TlsStream<AddrStream>>>, tokio::time::error::Elapsed> {
+        let accepted = futures_util::future::poll_fn(|cx| Pin::new(&mut *incoming).poll_accept(cx));
+        tokio::time::timeout(wait, accepted).await
+    }
+
+    #[tokio::test]
+    async fn test_max_handshakes() {
+        let (certificate, tls_options) = write_certificate("exporter-max-handshakes");
+        let acceptor = acceptor(&tls_options, HttpVersions::Http1).unwrap();
+        remove_certificate(&tls_options);
+
+        let incoming = AddrIncoming::bind(&([127, 0, 0, 1], 0).into()).unwrap();
+        let addr = incoming.local_addr();
+        let mut incoming = TlsIncoming::new(incoming, acceptor);
+        incoming.max_handshakes = 1;
+
+        // a client never starting its handshake takes the only slot
+        let stalled = TcpStream::connect(addr).await.unwrap();
+        assert!(accept(&mut incoming, Duration::from_millis(100))
+            .await
+            .is_err());
+
+        let client = tokio::spawn(async move { connect(addr, &certificate, &[]).await });
+        assert!(accept(&mut incoming, Duration::from_millis(200))
+            .await
+            .is_err());
+        assert!(!client.is_finished());
+
+        drop(stalled);
+        let accepted = accept(&mut incoming, Duration::from_secs(5)).await.unwrap();
+        assert!(matches!(accepted, Some(Ok(_))));
+        assert!(client.await.unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_acceptor_errors() {
+        let missing = TlsOptions::new("/nonexistent/cert.pem", "/nonexistent/key.pem");
+        assert!(matches!(
+            acceptor(&missing, HttpVersions::Http1),
+            Err(err) if err.to_string().starts_with("cannot open /nonexistent/cert.pem")
+        ));
+
+        let not_pem = std::env::temp_dir().join(format!("exporter-{}.pem", std::process::id()));
+        std::fs::write(&not_pem, "not a certificate").unwrap();
+        let empty = TlsOptions::new(&not_pem, &not_pem);
+        assert!(matches!(
+            acceptor(&empty, HttpVersions::Http1),
+            Err(err) if err.to_string().starts_with("no certificate found")
+        ));
+        std::fs::remove_file(&not_pem).unwrap();
+    }
+}